@@ -1,4 +1,6 @@
 use bytes::{Buf, BufMut, BytesMut};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use log::{debug, error, info, warn};
 use seahash::hash;
 
@@ -7,6 +9,8 @@ use std::fs::{self, OpenOptions};
 use std::io::{self, copy, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -14,18 +18,76 @@ pub enum Error {
     Io(#[from] io::Error),
     #[error("Not a backup file")]
     NotBackup,
+    #[error("Unsupported version of backup file format: {0}")]
+    UnsupportedVersion(u8),
     #[error("Corrupted backup file")]
     CorruptedFile,
     #[error("Empty write buffer")]
     NoWrites,
+    #[error("Encrypted backup file, but no key to decrypt it")]
+    MissingKey,
+    #[error("Failed to encrypt backup file")]
+    Encryption,
 }
 
+/// Codec used to compress segments before they are written onto disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    Disabled,
+    Lz4,
+    /// Zstandard compression at the given level, with a dictionary if one is set
+    Zstd(i32),
+}
+
+/// Order in which persisted data is read back
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ReadOrder {
+    #[default]
+    OldestFirst,
+    /// Data in memory and the latest files are read before older files
+    NewestFirst,
+}
+
+/// Records that were recovered from and dropped in a torn persistence file
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Salvage {
+    pub salvaged: usize,
+    pub dropped: usize,
+}
+
+/// Magic bytes at the start of every persistence file, followed by the format version. Files
+/// without it were written by older versions, as an 8 byte hash followed by raw bytes.
+const MAGIC: [u8; 4] = *b"UPLK";
+
+/// Version of the format in which persistence files are written. Version 1 header is followed by
+/// flags and the number of records. Each record is written as its length, hash and data, so that
+/// records before a damaged point in the file can be recovered.
+const FORMAT_VERSION: u8 = 1;
+
+/// Flag set when each record is lz4 compressed
+const LZ4_FLAG: u8 = 1;
+
+/// Flag set when each record is encrypted, data of a record is prefixed with its nonce
+const ENCRYPTED_FLAG: u8 = 1 << 1;
+
+/// Flag set when each record is zstd compressed
+const ZSTD_FLAG: u8 = 1 << 2;
+
+/// Size of the nonce used with XChaCha20Poly1305
+const NONCE_LEN: usize = 24;
+
+/// Name of the file holding committed read cursor of the oldest unacknowledged segment
+const CURSOR_FILE: &str = "read_cursor";
+
 pub struct Storage {
     name: String,
     /// maximum allowed file size
     max_file_size: usize,
     /// current open file
     current_write_file: BytesMut,
+    /// offsets in current write file at which records written into it end
+    record_ends: Vec<usize>,
     /// current_read_file
     current_read_file: BytesMut,
     /// disk persistence
@@ -38,6 +100,7 @@ impl Storage {
             name: name.into(),
             max_file_size,
             current_write_file: BytesMut::with_capacity(max_file_size * 2),
+            record_ends: vec![],
             current_read_file: BytesMut::with_capacity(max_file_size * 2),
             persistence: None,
         }
@@ -53,14 +116,64 @@ impl Storage {
         Ok(())
     }
 
+    /// Retain files after they are read, until the data read from them is acknowledged with [`ack`]
+    ///
+    /// [`ack`]: Storage::ack
     pub fn set_non_destructive_read(&mut self, switch: bool) {
         self.persistence.as_mut().unwrap().non_destructive_read = switch;
     }
 
+    /// Leave files that can't be read in place, instead of moving them out of the backlog directory
+    pub fn set_read_only(&mut self, switch: bool) {
+        self.persistence.as_mut().unwrap().read_only = switch;
+    }
+
+    /// Compress segments with the given codec when flushing them onto disk
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.persistence.as_mut().unwrap().compression = compression;
+    }
+
+    /// Dictionary to compress segments with zstd and to decompress them on read
+    pub fn set_zstd_dictionary(&mut self, dictionary: Vec<u8>) {
+        self.persistence.as_mut().unwrap().zstd_dictionary = Some(dictionary.into());
+    }
+
+    /// Order in which files are read back from disk
+    pub fn set_read_order(&mut self, order: ReadOrder) {
+        self.persistence.as_mut().unwrap().read_order = order;
+    }
+
+    /// Encrypt segments written onto disk and decrypt them on read with the given 256-bit key
+    pub fn set_encryption_key(&mut self, key: [u8; 32]) {
+        self.persistence.as_mut().unwrap().encryption_key = Some(key);
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Handle to write a record into, each call starts a new record
     pub fn writer(&mut self) -> &mut BytesMut {
+        self.end_record();
         &mut self.current_write_file
     }
 
+    /// Marks the end of data written since the previous record
+    fn end_record(&mut self) {
+        let len = self.current_write_file.len();
+        if len > self.record_ends.last().copied().unwrap_or(0) {
+            self.record_ends.push(len);
+        }
+    }
+
+    /// Number of records salvaged from and dropped in torn files since the previous call
+    pub fn take_salvage(&mut self) -> Salvage {
+        match &mut self.persistence {
+            Some(persistence) => mem::take(&mut persistence.salvage),
+            None => Salvage::default(),
+        }
+    }
+
     pub fn reader(&mut self) -> &mut BytesMut {
         &mut self.current_read_file
     }
@@ -103,6 +216,8 @@ impl Storage {
             return Err(Error::NoWrites);
         }
 
+        self.end_record();
+        let record_ends = mem::take(&mut self.record_ends);
         let Some(persistence) = &mut self.persistence else {
             // TODO(RT): Make sure that disk files starts with id 1 to represent in memory file
             // with id 0
@@ -116,15 +231,78 @@ impl Storage {
 
         let NextFile { mut file, deleted } = persistence.open_next_write_file()?;
         info!("Flushing data to disk for stoarge: {}; path = {:?}", self.name, file.path());
-        file.write(&mut self.current_write_file)?;
-
-        // 8 is the number of bytes the hash(u64) occupies
-        persistence.bytes_occupied += 8 + self.current_write_file.len();
+        persistence.bytes_occupied +=
+            file.write_records(&mut self.current_write_file, &record_ends)?;
         self.current_write_file.clear();
 
         Ok(deleted)
     }
 
+    /// Deletes the oldest file in backlog to free up disk space, the file being read is left as is.
+    /// Returns id of the deleted file, if any
+    pub fn delete_oldest_file(&mut self) -> Result<Option<u64>, Error> {
+        let Some(persistence) = &mut self.persistence else {
+            return Ok(None);
+        };
+
+        let Some(id) = persistence.backlog_files.pop_front() else {
+            return Ok(None);
+        };
+
+        let deleted_file = persistence.remove(id)?;
+        warn!(
+            "Deleting backup@{id} to free up disk space; storage = {}, path = {deleted_file:?}",
+            self.name
+        );
+
+        Ok(Some(id))
+    }
+
+    /// Deletes files in backlog that were written onto disk longer than `max_age` ago, the file being
+    /// read is left as is. Returns the number of deleted files
+    pub fn delete_expired(&mut self, max_age: Duration) -> Result<usize, Error> {
+        let Some(persistence) = &mut self.persistence else {
+            return Ok(0);
+        };
+
+        let mut deleted = 0;
+        while let Some(&id) = persistence.backlog_files.front() {
+            // NOTE: files are written in order of their ids, so later files are fresher
+            let path = persistence.path.join(format!("backup@{id}"));
+            let age = fs::metadata(path)?.modified()?.elapsed().unwrap_or_default();
+            if age <= max_age {
+                break;
+            }
+
+            persistence.backlog_files.pop_front();
+            let deleted_file = persistence.remove(id)?;
+            warn!("Deleting expired backup@{id}; storage = {}, path = {deleted_file:?}", self.name);
+            deleted += 1;
+        }
+
+        Ok(deleted)
+    }
+
+    /// Commits `len` bytes read from storage as acknowledged, in non-destructive read mode.
+    /// Advances the committed read cursor, files are deleted once all of their data is acknowledged.
+    /// Cursor is written to disk only when a file is completely acknowledged, see [`save_cursor`]
+    ///
+    /// [`save_cursor`]: Storage::save_cursor
+    pub fn ack(&mut self, len: usize) -> Result<(), Error> {
+        match &mut self.persistence {
+            Some(persistence) if persistence.non_destructive_read => persistence.ack(len),
+            _ => Ok(()),
+        }
+    }
+
+    /// Writes the committed read cursor to disk, if it moved since it was last written
+    pub fn save_cursor(&mut self) -> Result<(), Error> {
+        match &mut self.persistence {
+            Some(persistence) if persistence.cursor_dirty => persistence.save_cursor(),
+            _ => Ok(()),
+        }
+    }
+
     /// Loads head file to current inmemory read buffer. Deletes
     /// the file after loading. If all the disk data is caught up,
     /// swaps current write buffer to current read buffer if there
@@ -147,8 +325,25 @@ impl Storage {
 
             // Swap read buffer with write buffer to read data in inmemory write
             // buffer when all the backlog disk files are done
-            if persistence.backlog_files.is_empty() {
+            let read_memory = match persistence.read_order {
+                ReadOrder::OldestFirst => persistence.backlog_files.is_empty(),
+                // Alternate between freshest data in memory and the latest file on disk
+                ReadOrder::NewestFirst => {
+                    let fresh_data =
+                        !self.current_write_file.is_empty() && !persistence.read_memory;
+                    persistence.backlog_files.is_empty() || fresh_data
+                }
+            };
+            persistence.read_memory = read_memory;
+
+            if read_memory {
                 mem::swap(&mut self.current_read_file, &mut self.current_write_file);
+                self.record_ends.clear();
+                // Data that was never written onto disk is tracked to keep acks in order
+                let len = self.current_read_file.len();
+                if persistence.non_destructive_read && len > 0 {
+                    persistence.unacked.push_back(Segment { id: None, len, acked: 0 });
+                }
                 // If read buffer is 0 after swapping, all the data is caught up
                 return Ok(self.current_read_file.is_empty());
             }
@@ -159,9 +354,15 @@ impl Storage {
                 return Err(e);
             }
 
+            // All data in the file was acknowledged before a restart
+            if self.current_read_file.is_empty() {
+                return self.reload_on_eof();
+            }
+
             Ok(false)
         } else {
             mem::swap(&mut self.current_read_file, &mut self.current_write_file);
+            self.record_ends.clear();
             // If read buffer is 0 after swapping, all the data is caught up
             Ok(self.current_read_file.is_empty())
         }
@@ -170,16 +371,10 @@ impl Storage {
 
 /// Converts file path to file id
 fn id(path: &Path) -> Result<u64, Error> {
-    if let Some(file_name) = path.file_name() {
-        let file_name = format!("{file_name:?}");
-        if !file_name.contains("backup@") {
-            return Err(Error::NotBackup);
-        }
-    }
+    let file_name = path.file_name().and_then(|f| f.to_str()).ok_or(Error::NotBackup)?;
+    let id = file_name.strip_prefix("backup@").ok_or(Error::NotBackup)?;
 
-    let id: Vec<&str> = path.file_stem().unwrap().to_str().unwrap().split('@').collect();
-    let id: u64 = id[1].parse().unwrap();
-    Ok(id)
+    id.parse().map_err(|_| Error::NotBackup)
 }
 
 /// Gets list of file ids in the disk. Id of file backup@10 is 10.
@@ -195,7 +390,10 @@ fn get_file_ids(path: &Path) -> Result<VecDeque<u64>, Error> {
             continue;
         }
 
-        let Ok(id) = id(&path) else { continue };
+        let Ok(id) = id(&path) else {
+            debug!("Skipping file which isn't a backup: {path:?}");
+            continue;
+        };
         file_ids.push(id);
     }
 
@@ -205,17 +403,100 @@ fn get_file_ids(path: &Path) -> Result<VecDeque<u64>, Error> {
     Ok(file_ids)
 }
 
+/// Decodes the next record framed as length, hash and data. Returns `None` if the record is damaged
+fn decode_record(
+    buf: &mut BytesMut,
+    flags: u8,
+    cipher: Option<&XChaCha20Poly1305>,
+    dictionary: Option<&[u8]>,
+) -> Option<Vec<u8>> {
+    // 4 bytes of length and 8 bytes of hash
+    if buf.len() < 12 {
+        return None;
+    }
+
+    let len = buf.get_u32() as usize;
+    let expected_hash = buf.get_u64();
+    if buf.len() < len {
+        return None;
+    }
+
+    let record = buf.split_to(len);
+    if hash(&record) != expected_hash {
+        return None;
+    }
+
+    let record = match cipher {
+        Some(cipher) if record.len() >= NONCE_LEN => {
+            let (nonce, ciphertext) = record.split_at(NONCE_LEN);
+            cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()?
+        }
+        Some(_) => return None,
+        None => record.to_vec(),
+    };
+
+    if flags & LZ4_FLAG != 0 {
+        return lz4_flex::decompress_size_prepended(&record).ok();
+    }
+
+    if flags & ZSTD_FLAG != 0 {
+        let mut decompressed = vec![];
+        // Empty dictionary is the same as no dictionary
+        let dictionary = dictionary.unwrap_or_default();
+        let mut decoder = zstd::Decoder::with_dictionary(&record[..], dictionary).ok()?;
+        io::Read::read_to_end(&mut decoder, &mut decompressed).ok()?;
+        return Some(decompressed);
+    }
+
+    Some(record)
+}
+
 /// A handle to describe a persistence file on disk
 pub struct PersistenceFile<'a> {
     /// Path to the persistence directory
     dir: &'a Path,
     /// Name of the file e.g. `backup@1`
     file_name: String,
+    /// Codec used to compress contents on write
+    compression: Compression,
+    /// Key used to encrypt contents on write and decrypt them on read
+    encryption_key: Option<[u8; 32]>,
+    /// Dictionary used to compress contents with zstd on write and decompress them on read
+    zstd_dictionary: Option<Arc<[u8]>>,
+    /// Leave the file in place when it can't be read
+    read_only: bool,
 }
 
 impl<'a> PersistenceFile<'a> {
     pub fn new(dir: &'a Path, file_name: String) -> Result<Self, Error> {
-        Ok(Self { dir, file_name })
+        Ok(Self {
+            dir,
+            file_name,
+            compression: Compression::Disabled,
+            encryption_key: None,
+            zstd_dictionary: None,
+            read_only: false,
+        })
+    }
+
+    /// Compress contents with the given codec on write. Reads detect the codec from file header.
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+    }
+
+    /// Encrypt contents with the given key on write. Unencrypted files are still read as is.
+    pub fn set_encryption_key(&mut self, key: [u8; 32]) {
+        self.encryption_key = Some(key);
+    }
+
+    /// Compress contents with zstd using the given dictionary, which is also used to decompress on read
+    pub fn set_zstd_dictionary(&mut self, dictionary: Arc<[u8]>) {
+        self.zstd_dictionary = Some(dictionary);
+    }
+
+    /// Leave the file in place when it can't be read, instead of moving it aside
+    pub fn set_read_only(&mut self, switch: bool) {
+        self.read_only = switch;
     }
 
     /// Path of persistence file when stored on disk
@@ -225,19 +506,30 @@ impl<'a> PersistenceFile<'a> {
 
     // Moves the corrupt persistence file into special directory
     fn handle_corrupt_file(&self) -> Result<(), Error> {
+        self.move_aside("corrupted")
+    }
+
+    // Moves the persistence file out of the backlog directory, into the named sub-directory
+    fn move_aside(&self, dir: &str) -> Result<(), Error> {
         let path_src = self.path();
-        let dest_dir = self.dir.join("corrupted");
+        if self.read_only {
+            warn!("Leaving {dir} file in place: {path_src:?}");
+            return Ok(());
+        }
+
+        let dest_dir = self.dir.join(dir);
         fs::create_dir_all(&dest_dir)?;
         let path_dest = dest_dir.join(&self.file_name);
 
-        warn!("Moving corrupted file from {path_src:?} to {path_dest:?}");
+        warn!("Moving {dir} file from {path_src:?} to {path_dest:?}");
         fs::rename(path_src, path_dest)?;
 
         Ok(())
     }
 
-    /// Read contents of the persistence file from disk into buffer in memory
-    pub fn read(&mut self, buf: &mut BytesMut) -> Result<(), Error> {
+    /// Read contents of the persistence file from disk into buffer in memory. When the file is torn,
+    /// intact records are salvaged and the damaged file is moved out of the backlog directory.
+    pub fn read(&mut self, buf: &mut BytesMut) -> Result<Option<Salvage>, Error> {
         let path = self.path();
         let mut file = OpenOptions::new().read(true).open(path)?;
 
@@ -245,6 +537,29 @@ impl<'a> PersistenceFile<'a> {
         buf.clear();
         copy(&mut file, &mut buf.writer())?;
 
+        if !buf.starts_with(&MAGIC) {
+            return self.read_legacy(buf);
+        }
+
+        buf.advance(MAGIC.len());
+        if !buf.has_remaining() {
+            self.handle_corrupt_file()?;
+            return Err(Error::CorruptedFile);
+        }
+
+        // NOTE: files of unknown versions are moved aside, to be recovered with an upgrade
+        match buf.get_u8() {
+            FORMAT_VERSION => self.read_records(buf),
+            version => {
+                self.move_aside("unsupported")?;
+                Err(Error::UnsupportedVersion(version))
+            }
+        }
+    }
+
+    /// Migration path for files written by older versions without a versioned header, these are
+    /// checksummed as a whole
+    fn read_legacy(&mut self, buf: &mut BytesMut) -> Result<Option<Salvage>, Error> {
         // Verify with checksum
         if buf.len() < 8 {
             self.handle_corrupt_file()?;
@@ -258,20 +573,131 @@ impl<'a> PersistenceFile<'a> {
             return Err(Error::CorruptedFile);
         }
 
-        Ok(())
+        Ok(None)
+    }
+
+    /// Decodes individually framed records, upto the first damaged record
+    fn read_records(&mut self, buf: &mut BytesMut) -> Result<Option<Salvage>, Error> {
+        // 1 byte of flags and 4 bytes of record count
+        if buf.len() < 5 {
+            self.handle_corrupt_file()?;
+            return Err(Error::CorruptedFile);
+        }
+
+        let flags = buf.get_u8();
+        let count = buf.get_u32() as usize;
+        // NOTE: encrypted files are moved aside without a key, to be recovered once it is configured
+        let cipher = match &self.encryption_key {
+            _ if flags & ENCRYPTED_FLAG == 0 => None,
+            Some(key) => Some(XChaCha20Poly1305::new(key.into())),
+            None => {
+                self.move_aside("encrypted")?;
+                return Err(Error::MissingKey);
+            }
+        };
+
+        let mut records = BytesMut::with_capacity(buf.len());
+        let mut salvaged = 0;
+        let dictionary = self.zstd_dictionary.as_deref();
+        while salvaged < count {
+            let Some(record) = decode_record(buf, flags, cipher.as_ref(), dictionary) else {
+                break;
+            };
+            records.extend_from_slice(&record);
+            salvaged += 1;
+        }
+
+        buf.clear();
+        buf.extend_from_slice(&records);
+        if salvaged == count {
+            return Ok(None);
+        }
+
+        warn!("Salvaged {salvaged} of {count} records from torn file: {:?}", self.path());
+        self.handle_corrupt_file()?;
+
+        Ok(Some(Salvage { salvaged, dropped: count - salvaged }))
+    }
+
+    /// Write contents of buffer from memory onto the persistence file in disk, as a single record.
+    /// Returns the number of bytes occupied by the file on disk.
+    pub fn write(&mut self, buf: &mut BytesMut) -> Result<usize, Error> {
+        let len = buf.len();
+        self.write_records(buf, &[len])
     }
 
-    /// Write contents of buffer from memory onto the persistence file in disk
-    pub fn write(&mut self, buf: &mut BytesMut) -> Result<(), Error> {
+    /// Write records in buffer, ending at the given offsets, onto the persistence file in disk.
+    /// Each record is compressed, encrypted and checksummed individually.
+    /// Returns the number of bytes occupied by the file on disk.
+    pub fn write_records(
+        &mut self,
+        buf: &mut BytesMut,
+        record_ends: &[usize],
+    ) -> Result<usize, Error> {
         let path = self.path();
         let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(path)?;
 
-        let hash = hash(&buf[..]);
-        file.write_all(&hash.to_be_bytes())?;
-        file.write_all(&buf[..])?;
+        let mut flags = match self.compression {
+            Compression::Disabled => 0,
+            Compression::Lz4 => LZ4_FLAG,
+            Compression::Zstd(_) => ZSTD_FLAG,
+        };
+        let cipher = self.encryption_key.as_ref().map(|key| XChaCha20Poly1305::new(key.into()));
+        if cipher.is_some() {
+            flags |= ENCRYPTED_FLAG;
+        }
+
+        let mut data = Vec::with_capacity(buf.len() + 10 + 12 * record_ends.len());
+        data.extend_from_slice(&MAGIC);
+        data.push(FORMAT_VERSION);
+        data.push(flags);
+        data.extend_from_slice(&(record_ends.len() as u32).to_be_bytes());
+
+        let mut start = 0;
+        for &end in record_ends {
+            let record = &buf[start..end];
+            start = end;
+
+            let compressed;
+            let record = match self.compression {
+                Compression::Disabled => record,
+                Compression::Lz4 => {
+                    compressed = lz4_flex::compress_prepend_size(record);
+                    &compressed[..]
+                }
+                Compression::Zstd(level) => {
+                    compressed = match &self.zstd_dictionary {
+                        Some(dictionary) => {
+                            zstd::bulk::Compressor::with_dictionary(level, dictionary)?
+                                .compress(record)?
+                        }
+                        None => zstd::bulk::compress(record, level)?,
+                    };
+                    &compressed[..]
+                }
+            };
+
+            let encrypted;
+            let record = match &cipher {
+                None => record,
+                Some(cipher) => {
+                    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+                    let ciphertext =
+                        cipher.encrypt(&nonce, record).map_err(|_| Error::Encryption)?;
+                    encrypted = [&nonce[..], &ciphertext[..]].concat();
+                    &encrypted[..]
+                }
+            };
+
+            data.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            data.extend_from_slice(&hash(record).to_be_bytes());
+            data.extend_from_slice(record);
+        }
+
+        file.write_all(&data)?;
         file.flush()?;
 
-        Ok(())
+        Ok(data.len())
     }
 
     /// Deletes the persistence file from disk
@@ -293,6 +719,16 @@ struct NextFile<'a> {
     deleted: Option<u64>,
 }
 
+/// Data read from storage in non-destructive read mode, that is yet to be acknowledged
+struct Segment {
+    /// id of the file, `None` if the data was never written to disk or the file was deleted
+    id: Option<u64>,
+    /// size of data in the segment
+    len: usize,
+    /// size of data acknowledged, i.e. the committed read cursor
+    acked: usize,
+}
+
 struct Persistence {
     /// Backup path
     path: PathBuf,
@@ -307,6 +743,26 @@ struct Persistence {
     non_destructive_read: bool,
     /// Disk space(in bytes) currently occupied by persistence files
     bytes_occupied: usize,
+    /// Codec used to compress files written onto disk
+    compression: Compression,
+    /// Key used to encrypt/decrypt files on disk
+    encryption_key: Option<[u8; 32]>,
+    /// Dictionary used to compress/decompress files on disk with zstd
+    zstd_dictionary: Option<Arc<[u8]>>,
+    /// Files that can't be read are left in place, instead of being moved aside
+    read_only: bool,
+    /// Segments read in non-destructive read mode, that are waiting on acknowledgements
+    unacked: VecDeque<Segment>,
+    /// Committed read cursor loaded from disk, as file id and offset within the file
+    cursor: Option<(u64, usize)>,
+    /// Committed read cursor moved since it was last written to disk
+    cursor_dirty: bool,
+    /// Records salvaged from and dropped in torn files
+    salvage: Salvage,
+    /// Order in which backlog files are read
+    read_order: ReadOrder,
+    /// True if data being read was swapped in from write buffer
+    read_memory: bool,
 }
 
 impl Persistence {
@@ -322,6 +778,16 @@ impl Persistence {
             fs::metadata(&file).unwrap().len() as usize + acc
         });
 
+        // NOTE: A missing or unreadable cursor only leads to data being redelivered. Cursor is
+        // valid only for the oldest file, file ids restart from 0 when backlog is empty
+        let cursor = fs::read_to_string(path.join(CURSOR_FILE))
+            .ok()
+            .and_then(|cursor| {
+                let (id, offset) = cursor.trim().split_once(' ')?;
+                Some((id.parse().ok()?, offset.parse().ok()?))
+            })
+            .filter(|(id, _)| backlog_files.front() == Some(id));
+
         Ok(Persistence {
             path,
             max_file_count,
@@ -330,6 +796,16 @@ impl Persistence {
             // deleted: None,
             non_destructive_read: false,
             bytes_occupied,
+            compression: Compression::Disabled,
+            encryption_key: None,
+            zstd_dictionary: None,
+            read_only: false,
+            unacked: VecDeque::new(),
+            cursor,
+            cursor_dirty: false,
+            salvage: Salvage::default(),
+            read_order: ReadOrder::OldestFirst,
+            read_memory: false,
         })
     }
 
@@ -351,24 +827,31 @@ impl Persistence {
         self.backlog_files.push_back(next_file_id);
         let mut backlog_files_count = self.backlog_files.len();
 
-        // File being read is also to be considered
-        if self.current_read_file_id.is_some() {
+        if self.non_destructive_read {
+            // Files which are read but not yet acknowledged are also to be considered
+            backlog_files_count += self.unacked.iter().filter(|s| s.id.is_some()).count();
+        } else if self.current_read_file_id.is_some() {
+            // File being read is also to be considered
             backlog_files_count += 1
         }
 
         // Delete earliest file if backlog limits crossed
         let deleted = if backlog_files_count > self.max_file_count {
-            // Remove file being read, or first in backlog
-            // NOTE: keeps read buffer unchanged
-            let id = match self.current_read_file_id.take() {
-                Some(id) => id,
-                _ => self.backlog_files.pop_front().unwrap(),
+            // Remove earliest unacknowledged file, file being read, or first in backlog
+            // NOTE: keeps read buffer unchanged. Segment of a removed file is retained for
+            // acks on data already read from it to remain in order
+            let id = if self.read_order == ReadOrder::NewestFirst && self.backlog_files.len() > 1 {
+                // Files being read are newer than the ones in backlog
+                None
+            } else if self.non_destructive_read {
+                self.unacked.iter_mut().find_map(|s| s.id.take())
+            } else {
+                self.current_read_file_id.take()
             };
+            let id = id.unwrap_or_else(|| self.backlog_files.pop_front().unwrap());
 
-            if !self.non_destructive_read {
-                let deleted_file = self.remove(id)?;
-                warn!("file limit reached. deleting backup@{id}; path = {deleted_file:?}");
-            }
+            let deleted_file = self.remove(id)?;
+            warn!("file limit reached. deleting backup@{id}; path = {deleted_file:?}");
 
             Some(id)
         } else {
@@ -376,19 +859,119 @@ impl Persistence {
         };
 
         let file_name = format!("backup@{next_file_id}");
-        Ok(NextFile { file: PersistenceFile::new(&self.path, file_name)?, deleted })
+        let mut file = PersistenceFile::new(&self.path, file_name)?;
+        file.set_compression(self.compression);
+        if let Some(key) = self.encryption_key {
+            file.set_encryption_key(key);
+        }
+        if let Some(dictionary) = &self.zstd_dictionary {
+            file.set_zstd_dictionary(dictionary.clone());
+        }
+
+        Ok(NextFile { file, deleted })
     }
 
     /// Load the next persistence file to be read into memory
     fn load_next_read_file(&mut self, current_read_file: &mut BytesMut) -> Result<(), Error> {
         // Len always > 0 because of above if. Doesn't panic
-        let id = self.backlog_files.pop_front().unwrap();
+        let id = match self.read_order {
+            ReadOrder::OldestFirst => self.backlog_files.pop_front().unwrap(),
+            ReadOrder::NewestFirst => self.backlog_files.pop_back().unwrap(),
+        };
         let file_name = format!("backup@{id}");
         let mut file = PersistenceFile::new(&self.path, file_name)?;
+        if let Some(key) = self.encryption_key {
+            file.set_encryption_key(key);
+        }
+        if let Some(dictionary) = &self.zstd_dictionary {
+            file.set_zstd_dictionary(dictionary.clone());
+        }
+        file.set_read_only(self.read_only);
+        let size = fs::metadata(file.path())?.len() as usize;
 
         // Load file into memory and store its id for deleting in the future
-        file.read(current_read_file)?;
-        self.current_read_file_id = Some(id);
+        let read_file_id = match file.read(current_read_file) {
+            // Corrupted file, file of an unknown version or encrypted file without a key to decrypt it
+            // has been moved out of the backlog directory
+            Err(e @ (Error::CorruptedFile | Error::UnsupportedVersion(_) | Error::MissingKey)) => {
+                self.bytes_occupied -= size;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+            Ok(None) => Some(id),
+            // Torn file has been moved out of the backlog directory, after salvaging intact records
+            Ok(Some(salvage)) => {
+                self.bytes_occupied -= size;
+                self.salvage.salvaged += salvage.salvaged;
+                self.salvage.dropped += salvage.dropped;
+                if current_read_file.is_empty() {
+                    return Err(Error::CorruptedFile);
+                }
+
+                None
+            }
+        };
+        self.current_read_file_id = read_file_id;
+
+        let len = current_read_file.len();
+        // Skip data that was acknowledged before a restart
+        let acked = match self.cursor.take() {
+            Some((cursor_id, offset)) if cursor_id == id => offset.min(len),
+            _ => 0,
+        };
+        current_read_file.advance(acked);
+
+        if self.non_destructive_read {
+            self.unacked.push_back(Segment { id: read_file_id, len, acked });
+        }
+
+        Ok(())
+    }
+
+    /// Advances the committed read cursor by `len` bytes, deleting completely acknowledged files.
+    /// Cursor is written to disk when a file is deleted, as the cursor pointing into it is stale.
+    fn ack(&mut self, mut len: usize) -> Result<(), Error> {
+        let mut deleted = false;
+        while let Some(segment) = self.unacked.front_mut() {
+            let n = len.min(segment.len - segment.acked);
+            segment.acked += n;
+            len -= n;
+            self.cursor_dirty |= n > 0;
+            if segment.acked < segment.len {
+                break;
+            }
+
+            if let Some(id) = self.unacked.pop_front().and_then(|s| s.id) {
+                let deleted_file = self.remove(id)?;
+                debug!("Completed acknowledging a persistence file, deleting it; path = {deleted_file:?}");
+                deleted = true;
+            }
+        }
+
+        if deleted {
+            self.save_cursor()?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes cursor within the oldest unacknowledged file to disk, to survive restarts. Cursor is
+    /// written into a temporary file that is then renamed, so that a crash doesn't leave it torn.
+    fn save_cursor(&mut self) -> Result<(), Error> {
+        let cursor_file = self.path.join(CURSOR_FILE);
+        match self.unacked.front() {
+            Some(Segment { id: Some(id), acked, .. }) if *acked > 0 => {
+                let tmp_file = self.path.join(format!("{CURSOR_FILE}.tmp"));
+                let mut file =
+                    OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_file)?;
+                file.write_all(format!("{id} {acked}").as_bytes())?;
+                file.sync_all()?;
+                fs::rename(tmp_file, cursor_file)?;
+            }
+            _ if cursor_file.exists() => fs::remove_file(cursor_file)?,
+            _ => {}
+        }
+        self.cursor_dirty = false;
 
         Ok(())
     }
@@ -400,6 +983,9 @@ mod test {
     use rumqttc::*;
     use tempdir::TempDir;
 
+    /// Magic, version, flags and record count
+    const HEADER_LEN: usize = 10;
+
     fn init_backup_folders() -> TempDir {
         let backup = TempDir::new("/tmp/persist").unwrap();
 
@@ -572,4 +1158,231 @@ mod test {
         let files = get_file_ids(&backup.path()).unwrap();
         assert_eq!(files, vec![10, 11, 12, 13, 14, 15, 16, 17, 18, 19]);
     }
+
+    #[test]
+    fn files_are_retained_until_read_data_is_acked() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        storage.set_non_destructive_read(true);
+
+        // 2 files on disk
+        write_n_publishes(&mut storage, 20);
+
+        // Read all of file 0 and half of file 1, but only half of file 0 is acked
+        read_n_publishes(&mut storage, 15);
+        storage.ack(5 * 1036).unwrap();
+        assert_eq!(get_file_ids(backup.path()).unwrap(), vec![0, 1]);
+
+        // Cursor is only written to disk when saved
+        assert!(!backup.path().join(CURSOR_FILE).exists());
+        storage.save_cursor().unwrap();
+        assert_eq!(fs::read_to_string(backup.path().join(CURSOR_FILE)).unwrap(), "0 5180");
+
+        // Unacked data is read again after a restart
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        storage.set_non_destructive_read(true);
+        let publishes = read_n_publishes(&mut storage, 15);
+        assert_eq!(publishes.len(), 15);
+        assert_eq!(publishes[0].payload[0], 5);
+
+        // File is deleted once all of its data is acked
+        storage.ack(5 * 1036).unwrap();
+        assert_eq!(get_file_ids(backup.path()).unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn compressed_files_are_read_back_along_with_uncompressed_files() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+
+        // 2 uncompressed files on disk, followed by 3 compressed files
+        write_n_publishes(&mut storage, 20);
+        storage.set_compression(Compression::Lz4);
+        write_n_publishes(&mut storage, 30);
+
+        let files = get_file_ids(&backup.path()).unwrap();
+        assert_eq!(files, vec![0, 1, 2, 3, 4]);
+
+        // Repetitive payloads compress well, disk usage should reflect that
+        let on_disk: u64 = files
+            .iter()
+            .map(|id| fs::metadata(backup.path().join(format!("backup@{id}"))).unwrap().len())
+            .sum();
+        assert_eq!(storage.disk_utilized(), on_disk as usize);
+        assert!(storage.disk_utilized() < 3 * 10 * 1036);
+
+        let publishes = read_n_publishes(&mut storage, 50);
+        assert_eq!(publishes.len(), 50);
+        for (i, publish) in (0..20).chain(0..30).zip(publishes.iter()) {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+    }
+
+    #[test]
+    fn tampered_encrypted_file_is_moved_to_corrupted() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        storage.set_compression(Compression::Lz4);
+        storage.set_encryption_key([7; 32]);
+
+        // 2 encrypted files on disk
+        write_n_publishes(&mut storage, 20);
+
+        let path = backup.path().join("backup@0");
+        let mut contents = fs::read(&path).unwrap();
+        assert!(contents.starts_with(&MAGIC));
+        assert_eq!(contents[5], LZ4_FLAG | ENCRYPTED_FLAG);
+
+        // Flip a bit in the ciphertext of first record in the first file
+        contents[HEADER_LEN + 12 + NONCE_LEN] ^= 1;
+        fs::write(&path, contents).unwrap();
+
+        // First file fails authentication and is moved aside, second is read as usual
+        assert!(matches!(storage.reload_on_eof(), Err(super::Error::CorruptedFile)));
+        assert!(backup.path().join("corrupted").join("backup@0").is_file());
+
+        let publishes = read_n_publishes(&mut storage, 20);
+        assert_eq!(publishes.len(), 10);
+        for (i, publish) in (10..20).zip(publishes.iter()) {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+    }
+
+    #[test]
+    fn encrypted_file_without_key_is_moved_aside() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        storage.set_encryption_key([7; 32]);
+
+        // 1 encrypted file on disk
+        write_n_publishes(&mut storage, 10);
+        drop(storage);
+
+        // Storage without a key moves the file aside and no longer counts it in disk usage
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        assert!(matches!(storage.reload_on_eof(), Err(super::Error::MissingKey)));
+        assert!(backup.path().join("encrypted").join("backup@0").is_file());
+        assert_eq!(get_file_ids(backup.path()).unwrap(), vec![]);
+        assert_eq!(storage.disk_utilized(), 0);
+        assert_eq!(storage.file_count(), 0);
+        assert!(storage.reload_on_eof().unwrap());
+    }
+
+    #[test]
+    fn unreadable_files_are_left_in_place_when_read_only() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        storage.set_encryption_key([7; 32]);
+
+        // 2 encrypted files on disk
+        write_n_publishes(&mut storage, 20);
+        drop(storage);
+
+        // Neither file can be read without the key, both are left in place
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        storage.set_non_destructive_read(true);
+        storage.set_read_only(true);
+        assert!(matches!(storage.reload_on_eof(), Err(super::Error::MissingKey)));
+        assert!(matches!(storage.reload_on_eof(), Err(super::Error::MissingKey)));
+        assert!(storage.reload_on_eof().unwrap());
+        assert!(!backup.path().join("encrypted").exists());
+        assert_eq!(get_file_ids(backup.path()).unwrap(), vec![0, 1]);
+    }
+
+    #[test]
+    fn intact_records_are_salvaged_from_torn_file() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+
+        // 2 files on disk
+        write_n_publishes(&mut storage, 20);
+
+        // Tear the first file in the middle of its 5th record, each record is framed in 12 bytes
+        let path = backup.path().join("backup@0");
+        let contents = fs::read(&path).unwrap();
+        fs::write(&path, &contents[..HEADER_LEN + 4 * (12 + 1036) + 500]).unwrap();
+
+        let publishes = read_n_publishes(&mut storage, 20);
+        assert_eq!(publishes.len(), 14);
+        let payloads = (0..4).chain(10..20);
+        for (i, publish) in payloads.zip(publishes.iter()) {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+
+        assert_eq!(storage.take_salvage(), Salvage { salvaged: 4, dropped: 6 });
+        assert!(backup.path().join("corrupted").join("backup@0").is_file());
+    }
+
+    #[test]
+    fn legacy_files_are_migrated_and_odd_files_skipped() {
+        let backup = init_backup_folders();
+
+        // Legacy file with an 8 byte hash followed by raw publishes
+        let mut data = BytesMut::new();
+        for i in 0..5 {
+            let mut publish = Publish::new("hello", QoS::AtLeastOnce, vec![i; 1024]);
+            publish.pkid = 1;
+            publish.write(&mut data).unwrap();
+        }
+        let mut legacy = hash(&data).to_be_bytes().to_vec();
+        legacy.extend_from_slice(&data);
+        fs::write(backup.path().join("backup@0"), legacy).unwrap();
+
+        // Files with names that can't be parsed and a file from an unknown future version
+        for name in ["backup@x", "backup@", "backup@1.tmp", "hello"] {
+            fs::write(backup.path().join(name), b"hello").unwrap();
+        }
+        fs::write(backup.path().join("backup@1"), [&MAGIC[..], &[u8::MAX]].concat()).unwrap();
+
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        assert_eq!(get_file_ids(backup.path()).unwrap(), vec![0, 1]);
+
+        let publishes = read_n_publishes(&mut storage, 5);
+        for (i, publish) in (0..5).zip(publishes.iter()) {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+
+        // File of an unknown version is moved aside and no longer counted in disk usage
+        assert!(matches!(storage.reload_on_eof(), Err(super::Error::UnsupportedVersion(u8::MAX))));
+        assert!(backup.path().join("unsupported").join("backup@1").is_file());
+        assert_eq!(get_file_ids(backup.path()).unwrap(), vec![]);
+        assert_eq!(storage.disk_utilized(), 0);
+        assert_eq!(storage.file_count(), 0);
+        assert!(storage.reload_on_eof().unwrap());
+    }
+
+    #[test]
+    fn newest_data_is_read_first() {
+        let backup = init_backup_folders();
+        let mut storage = Storage::new("test", 10 * 1036);
+        storage.set_persistence(backup.path(), 10).unwrap();
+        storage.set_read_order(ReadOrder::NewestFirst);
+
+        // 2 files on disk and 5 publishes in memory
+        write_n_publishes(&mut storage, 25);
+
+        // Data in memory, followed by latest file
+        let publishes = read_n_publishes(&mut storage, 15);
+        for (i, publish) in (20..25).chain(10..20).zip(publishes.iter()) {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+
+        // Data written while reading is read before older files
+        write_n_publishes(&mut storage, 3);
+        let publishes = read_n_publishes(&mut storage, 20);
+        assert_eq!(publishes.len(), 13);
+        for (i, publish) in (0..3).chain(0..10).zip(publishes.iter()) {
+            assert_eq!(&publish.payload[..], vec![i as u8; 1024].as_slice());
+        }
+    }
 }
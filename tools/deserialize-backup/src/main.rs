@@ -11,11 +11,13 @@ use human_bytes::human_bytes;
 use lz4_flex::frame::FrameDecoder;
 use rumqttc::{read, Packet};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use structopt::StructOpt;
 use tabled::{
     settings::{locator::ByColumnName, Disable, Style},
     Table, Tabled,
 };
+use uplink::base::bridge::Columns;
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "simulator", about = "simulates a demo device")]
@@ -32,6 +34,24 @@ pub struct CommandLine {
         help = "Write file contents in human readable form into this directory"
     )]
     pub human_readable: Option<PathBuf>,
+    /// File containing hex encoded key, to decrypt encrypted backups
+    #[structopt(short = "k", help = "File containing hex encoded key to decrypt backups")]
+    pub key_file: Option<PathBuf>,
+    /// Zstd dictionaries of streams, to decompress persistence files and payloads of streams
+    /// compressed with a dictionary
+    #[structopt(
+        short = "z",
+        parse(try_from_str = parse_dictionary),
+        help = "Zstd dictionary of a stream as <stream>=<path>, to decompress its persistence files and payloads with"
+    )]
+    pub dictionaries: Vec<(String, PathBuf)>,
+}
+
+fn parse_dictionary(arg: &str) -> Result<(String, PathBuf), String> {
+    let (stream, path) =
+        arg.split_once('=').ok_or_else(|| format!("expected <stream>=<path>, found: {arg}"))?;
+
+    Ok((stream.to_owned(), PathBuf::from(path)))
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -44,6 +64,14 @@ pub enum Error {
     FromUtf8(#[from] FromUtf8Error),
     #[error("Serde error {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("Cbor error {0}")]
+    Cbor(#[from] ciborium::de::Error<std::io::Error>),
+    #[error("MessagePack error {0}")]
+    MessagePack(#[from] rmp_serde::decode::Error),
+    #[error("Hex error {0}")]
+    Hex(#[from] hex::FromHexError),
+    #[error("Key should be 32 bytes long")]
+    KeyLength,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -58,6 +86,7 @@ struct Stream {
     uncompressed_size: usize,
     start: u64,
     end: u64,
+    serialization_format: String,
     compression_algo: String,
 }
 
@@ -69,6 +98,7 @@ impl Default for Stream {
             uncompressed_size: 0,
             start: u64::MAX,
             end: 0,
+            serialization_format: "jsonarray".to_string(),
             compression_algo: "".to_string(),
         }
     }
@@ -102,7 +132,7 @@ impl Entry {
 
         Self {
             stream_name,
-            serialization_format: "jsonarray".to_string(),
+            serialization_format: stream.serialization_format,
             count: stream.count,
             compression_algo: stream.compression_algo,
             message_rate,
@@ -139,6 +169,17 @@ impl HumanReadableFile {
 
 fn main() -> Result<(), Error> {
     let commandline: CommandLine = StructOpt::from_args();
+    let key = match &commandline.key_file {
+        Some(path) => {
+            let key = hex::decode(std::fs::read_to_string(path)?.trim())?;
+            Some(<[u8; 32]>::try_from(key).map_err(|_| Error::KeyLength)?)
+        }
+        _ => None,
+    };
+    let mut dictionaries = HashMap::new();
+    for (stream, path) in &commandline.dictionaries {
+        dictionaries.insert(stream.to_owned(), std::fs::read(path)?);
+    }
 
     let mut streams: HashMap<String, Stream> = HashMap::new();
     let mut total = Stream::default();
@@ -157,6 +198,15 @@ fn main() -> Result<(), Error> {
         let mut storage = storage::Storage::new(&stream_name, 1048576);
         storage.set_persistence(path, 3)?;
         storage.set_non_destructive_read(true);
+        // NOTE: files that can't be read are left as is, backups are only read by this tool
+        storage.set_read_only(true);
+        if let Some(key) = key {
+            storage.set_encryption_key(key);
+        }
+        let dictionary = dictionaries.get(&stream_name).cloned().unwrap_or_default();
+        if !dictionary.is_empty() {
+            storage.set_zstd_dictionary(dictionary.clone());
+        }
         let mut human_readable_file =
             commandline.human_readable.as_ref().map(|p| HumanReadableFile::new(p, &stream_name));
 
@@ -191,13 +241,45 @@ fn main() -> Result<(), Error> {
                 let mut bytes = vec![];
                 decompressor.read_to_end(&mut bytes)?;
                 publish.payload = Bytes::from(bytes);
+            } else if publish.topic.ends_with("zstd") {
+                stream.compression_algo = "zstd".to_owned();
+                let mut decompressor =
+                    zstd::Decoder::with_dictionary(&*publish.payload, &dictionary)?;
+                let mut bytes = vec![];
+                decompressor.read_to_end(&mut bytes)?;
+                publish.payload = Bytes::from(bytes);
             }
 
             stream.uncompressed_size += publish.payload.len();
 
-            let payloads: Vec<Payload> = serde_json::from_slice(&publish.payload)?;
+            let (encoding, batch): (_, Value) = if publish.topic.contains("/cborarray") {
+                ("cbor", ciborium::from_reader(&*publish.payload)?)
+            } else if publish.topic.contains("/msgpackarray") {
+                ("msgpack", rmp_serde::from_slice(&publish.payload)?)
+            } else {
+                ("json", serde_json::from_slice(&publish.payload)?)
+            };
+
+            // Batches of columnar streams are objects, instead of arrays of data points
+            let payloads = match batch {
+                Value::Array(payloads) => {
+                    stream.serialization_format = format!("{encoding}array");
+                    payloads
+                }
+                batch => {
+                    stream.serialization_format = format!("{encoding}columns");
+                    serde_json::from_value::<Columns>(batch)?.into_points()
+                }
+            };
+
+            // Write human readable
+            if let Some(HumanReadableFile { file }) = &mut human_readable_file {
+                let text = serde_json::to_string(&payloads)?;
+                write!(file, "{}", text).unwrap();
+            }
+
             for payload in payloads {
-                let timestamp = payload.timestamp;
+                let Payload { timestamp } = serde_json::from_value(payload)?;
                 stream.count += 1;
                 if stream.start > timestamp {
                     stream.start = timestamp
@@ -206,13 +288,6 @@ fn main() -> Result<(), Error> {
                 if stream.end < timestamp {
                     stream.end = timestamp
                 }
-
-                // Write human readable
-                if let Some(HumanReadableFile { file }) = &mut human_readable_file {
-                    let text = String::from_utf8(publish.payload.to_vec())
-                        .expect("Should have been JSON text");
-                    write!(file, "{}", text).unwrap();
-                }
             }
         }
     }
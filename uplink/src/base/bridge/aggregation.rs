@@ -0,0 +1,155 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::{Map, Number, Value};
+
+use super::delaymap::DelayMap;
+use super::Payload;
+use crate::config::{Aggregate, AggregationConfig};
+
+/// Running summary of values a field took within a window
+#[derive(Debug, Default)]
+struct Summary {
+    /// Number of numeric values
+    count: usize,
+    min: f64,
+    max: f64,
+    mean: f64,
+    /// Sum of squared differences from the mean, ref: Welford's online algorithm
+    m2: f64,
+    last: Value,
+}
+
+impl Summary {
+    fn add(&mut self, value: &Value) {
+        self.last = value.clone();
+        let Some(x) = value.as_f64() else { return };
+
+        self.count += 1;
+        if self.count == 1 {
+            (self.min, self.max) = (x, x);
+        } else {
+            self.min = self.min.min(x);
+            self.max = self.max.max(x);
+        }
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    /// Aggregates of numeric functions are `null` if the field took no numeric values
+    fn get(&self, aggregate: Aggregate) -> Value {
+        let n = match aggregate {
+            Aggregate::Count => return Value::from(self.count),
+            Aggregate::Last => return self.last.clone(),
+            _ if self.count == 0 => return Value::Null,
+            Aggregate::Min => self.min,
+            Aggregate::Max => self.max,
+            Aggregate::Mean => self.mean,
+            Aggregate::Stddev => (self.m2 / self.count as f64).sqrt(),
+        };
+
+        Number::from_f64(n).map_or(Value::Null, Value::Number)
+    }
+}
+
+/// Window of a derived stream, open from the first data point received after the last flush
+#[derive(Debug)]
+struct Window {
+    config: AggregationConfig,
+    /// Timestamp of the first data point in window
+    start: Option<u64>,
+    sequence: u32,
+    summaries: BTreeMap<String, Summary>,
+}
+
+/// Aggregates data points of input streams over tumbling windows, into data points of derived streams
+pub struct Aggregations {
+    windows: HashMap<String, Window>,
+    /// Names of streams derived from each input stream
+    inputs: HashMap<String, Vec<String>>,
+    /// Windows of derived streams that are to be closed
+    pub timeouts: DelayMap<String>,
+}
+
+impl Aggregations {
+    pub fn new(config: &HashMap<String, AggregationConfig>) -> Self {
+        let mut inputs: HashMap<String, Vec<String>> = HashMap::new();
+        let mut windows = HashMap::new();
+        for (name, config) in config {
+            inputs.entry(config.input.to_owned()).or_default().push(name.to_owned());
+            let window = Window {
+                config: config.clone(),
+                start: None,
+                sequence: 0,
+                summaries: BTreeMap::new(),
+            };
+            windows.insert(name.to_owned(), window);
+        }
+
+        Self { windows, inputs, timeouts: DelayMap::new() }
+    }
+
+    /// Adds fields of a data point into windows of streams derived from its stream,
+    /// returns true if the raw data point is to be discarded
+    pub fn add(&mut self, data: &Payload) -> bool {
+        let Some(derived) = self.inputs.get(&data.stream) else { return false };
+        let Value::Object(fields) = &data.payload else { return false };
+
+        let mut discard = false;
+        for name in derived {
+            // Doesn't panic as windows are created for all derived streams
+            let window = self.windows.get_mut(name).unwrap();
+            discard |= window.config.discard_input;
+            if window.start.is_none() {
+                window.start = Some(data.timestamp);
+                self.timeouts.insert(name, window.config.window);
+            }
+
+            for (field, value) in fields {
+                if !window.config.fields.is_empty() && !window.config.fields.contains(field) {
+                    continue;
+                }
+                window.summaries.entry(field.to_owned()).or_default().add(value);
+            }
+        }
+
+        discard
+    }
+
+    /// Closes window of a derived stream, returns a data point with aggregates of its fields,
+    /// named as `<field>_<aggregate>`
+    pub fn flush(&mut self, name: &str) -> Option<Payload> {
+        let window = self.windows.get_mut(name)?;
+        let timestamp = window.start.take()?;
+        let summaries = std::mem::take(&mut window.summaries);
+
+        let mut payload = Map::new();
+        for (field, summary) in summaries {
+            for aggregate in window.config.aggregates.iter() {
+                payload.insert(format!("{field}_{}", aggregate.as_str()), summary.get(*aggregate));
+            }
+        }
+        window.sequence += 1;
+
+        Some(Payload {
+            stream: name.to_owned(),
+            sequence: window.sequence,
+            timestamp,
+            payload: Value::Object(payload),
+        })
+    }
+
+    /// Closes all open windows, e.g. on shutdown
+    pub fn flush_all(&mut self) -> Vec<Payload> {
+        let names: Vec<String> = self.windows.keys().cloned().collect();
+        let mut points = vec![];
+        for name in names {
+            if let Some(point) = self.flush(&name) {
+                self.timeouts.remove(&name);
+                points.push(point);
+            }
+        }
+
+        points
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use super::Point;
+
+/// Batch of data points laid out column-wise, where field names are written only once.
+/// Sequence numbers and timestamps are delta encoded, i.e. the first value is as is and every
+/// following value is the difference from the value of the previous point.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+pub struct Columns {
+    pub sequence: Vec<i64>,
+    pub timestamp: Vec<i64>,
+    /// Values of each field, `null` where a point doesn't contain the field
+    pub fields: Map<String, Value>,
+    /// Bitmaps of fields missing from some of the points, to tell them apart from explicit `null`s.
+    /// Bit `i % 8` of byte `i / 8` is set if the `i`th point contains the field.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub present: BTreeMap<String, Vec<u8>>,
+}
+
+impl Columns {
+    pub fn new<T: Point>(points: &[T]) -> Result<Columns, serde_json::Error> {
+        let mut columns = Columns::default();
+        // Values of each field along with whether each point contains it
+        let mut fields: Vec<(String, Vec<Value>, Vec<bool>)> = vec![];
+        let (mut last_sequence, mut last_timestamp) = (0, 0);
+
+        for (i, point) in points.iter().enumerate() {
+            let (sequence, timestamp) = (point.sequence() as i64, point.timestamp() as i64);
+            columns.sequence.push(sequence - last_sequence);
+            columns.timestamp.push(timestamp - last_timestamp);
+            (last_sequence, last_timestamp) = (sequence, timestamp);
+
+            // Points that aren't objects don't contain any of the fields
+            let map = match serde_json::to_value(point)? {
+                Value::Object(map) => map,
+                _ => Map::new(),
+            };
+            for (name, value) in map {
+                if name == "sequence" || name == "timestamp" {
+                    continue;
+                }
+
+                match fields.iter_mut().find(|(n, ..)| *n == name) {
+                    Some((_, values, present)) => {
+                        values.push(value);
+                        present.push(true);
+                    }
+                    // Field seen for the first time, earlier points don't contain it
+                    None => {
+                        let mut values = vec![Value::Null; i];
+                        values.push(value);
+                        let mut present = vec![false; i];
+                        present.push(true);
+                        fields.push((name, values, present));
+                    }
+                }
+            }
+
+            // Fields missing from this point
+            for (_, values, present) in fields.iter_mut() {
+                values.resize(i + 1, Value::Null);
+                present.resize(i + 1, false);
+            }
+        }
+
+        for (name, values, present) in fields {
+            if present.contains(&false) {
+                let mut bitmap = vec![0; present.len().div_ceil(8)];
+                for (i, _) in present.iter().enumerate().filter(|(_, p)| **p) {
+                    bitmap[i / 8] |= 1 << (i % 8);
+                }
+                columns.present.insert(name.clone(), bitmap);
+            }
+            columns.fields.insert(name, Value::Array(values));
+        }
+
+        Ok(columns)
+    }
+
+    /// Expands columns back into data points, leaving out fields missing from a point
+    pub fn into_points(self) -> Vec<Value> {
+        let (mut sequence, mut timestamp) = (0, 0);
+        let mut points = Vec::with_capacity(self.sequence.len());
+
+        for (i, (ds, dt)) in self.sequence.iter().zip(self.timestamp.iter()).enumerate() {
+            sequence += ds;
+            timestamp += dt;
+            let mut point = Map::new();
+            point.insert("sequence".to_owned(), sequence.into());
+            point.insert("timestamp".to_owned(), timestamp.into());
+            for (name, values) in self.fields.iter() {
+                if self.contains(name, i) {
+                    let value = values.get(i).cloned().unwrap_or_default();
+                    point.insert(name.to_owned(), value);
+                }
+            }
+            points.push(Value::Object(point));
+        }
+
+        points
+    }
+
+    /// Whether the `i`th point contains the field, as a field can be `null` without being missing
+    pub fn contains(&self, field: &str, i: usize) -> bool {
+        if !self.fields.contains_key(field) {
+            return false;
+        }
+
+        match self.present.get(field) {
+            Some(bitmap) => bitmap.get(i / 8).is_some_and(|b| b & (1 << (i % 8)) != 0),
+            None => i < self.sequence.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::{base::bridge::spill::SpilledPoint, Payload};
+
+    fn points(payloads: Vec<Value>) -> Vec<Payload> {
+        payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| Payload {
+                stream: "hello".to_owned(),
+                sequence: i as u32 + 1,
+                timestamp: 100 * i as u64,
+                payload,
+            })
+            .collect()
+    }
+
+    #[test]
+    // Ensures that columnar batches tell fields missing from a data point apart from explicit nulls
+    fn missing_fields_are_told_apart_from_nulls() {
+        let points = points(vec![
+            json!({ "a": 1, "b": null }),
+            json!({ "a": null }),
+            json!({ "a": 3, "b": 4 }),
+        ]);
+
+        let columns = Columns::new(&points).unwrap();
+        let expected = json!({
+            "sequence": [1, 1, 1],
+            "timestamp": [0, 100, 100],
+            "fields": { "a": [1, null, 3], "b": [null, null, 4] },
+            "present": { "b": [0b101] }
+        });
+        assert_eq!(serde_json::to_value(&columns).unwrap(), expected);
+
+        assert!(columns.contains("a", 1));
+        assert!(columns.contains("b", 0));
+        assert!(!columns.contains("b", 1));
+        assert!(columns.contains("b", 2));
+        assert!(!columns.contains("c", 0));
+    }
+
+    #[test]
+    // Ensures that data points are decoded from a serialized batch as they were encoded
+    fn points_are_decoded_as_encoded() {
+        let points = points(vec![
+            json!({ "a": 1, "b": null }),
+            json!({ "a": null }),
+            json!({ "c": "hello" }),
+            json!({ "a": 3, "b": 4, "c": null }),
+        ]);
+
+        let batch = serde_json::to_vec(&Columns::new(&points).unwrap()).unwrap();
+        let columns: Columns = serde_json::from_slice(&batch).unwrap();
+        let expected: Vec<Value> =
+            points.iter().map(|point| serde_json::to_value(point).unwrap()).collect();
+        assert_eq!(columns.into_points(), expected);
+    }
+
+    #[test]
+    // Ensures that columns stay in line with sequence and timestamp, around points that aren't objects
+    fn columns_are_aligned_around_non_object_points() {
+        let stream = Arc::new("hello".to_owned());
+        let points: Vec<SpilledPoint> =
+            [json!({ "sequence": 1, "a": 1 }), json!(5), json!({ "sequence": 3, "a": 3 })]
+                .into_iter()
+                .map(|point| SpilledPoint { stream: stream.clone(), point })
+                .collect();
+
+        let columns = Columns::new(&points).unwrap();
+        assert_eq!(columns.fields["a"], json!([1, null, 3]));
+        assert_eq!(columns.present["a"], vec![0b101]);
+
+        let points = columns.into_points();
+        assert_eq!(points.len(), 3);
+        assert_eq!(points[1], json!({ "sequence": 0, "timestamp": 0 }));
+        assert_eq!(points[2], json!({ "sequence": 3, "timestamp": 0, "a": 3 }));
+    }
+}
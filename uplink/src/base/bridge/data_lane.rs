@@ -1,12 +1,17 @@
 use std::sync::Arc;
+use std::time::Duration;
 
 use flume::{bounded, Receiver, RecvError, Sender};
 use log::{debug, error};
 use tokio::{select, time::interval};
 
+use crate::config::DEFAULT_TIMEOUT;
 use crate::Config;
 
-use super::{streams::Streams, DataBridgeShutdown, Package, Payload, StreamMetrics};
+use super::{
+    aggregation::Aggregations, streams::Streams, DataBridgeShutdown, DynamicStreams, Package,
+    Payload, StreamMetrics,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -23,6 +28,8 @@ pub struct DataBridge {
     data_rx: Receiver<Payload>,
     /// Handle to send data over streams
     streams: Streams<Payload>,
+    /// Windowed aggregates of streams, forwarded on derived streams
+    aggregations: Aggregations,
     ctrl_rx: Receiver<DataBridgeShutdown>,
     ctrl_tx: Sender<DataBridgeShutdown>,
 }
@@ -38,8 +45,11 @@ impl DataBridge {
 
         let mut streams = Streams::new(config.clone(), package_tx, metrics_tx);
         streams.config_streams(config.streams.clone());
+        streams.derived_streams(config.aggregations.keys());
 
-        Self { data_tx, data_rx, config, streams, ctrl_rx, ctrl_tx }
+        let aggregations = Aggregations::new(&config.aggregations);
+
+        Self { data_tx, data_rx, config, streams, aggregations, ctrl_rx, ctrl_tx }
     }
 
     /// Handle to send data points from source application
@@ -47,6 +57,11 @@ impl DataBridge {
         DataTx { inner: self.data_tx.clone() }
     }
 
+    /// Handle to register streams that aren't configured, before sending their data
+    pub fn dynamic_streams(&self) -> DynamicStreams {
+        self.streams.dynamic_streams()
+    }
+
     /// Handle to send data lane control message
     pub fn ctrl_tx(&self) -> CtrlTx {
         CtrlTx { inner: self.ctrl_tx.clone() }
@@ -54,12 +69,24 @@ impl DataBridge {
 
     pub async fn start(&mut self) -> Result<(), Error> {
         let mut metrics_timeout = interval(self.config.stream_metrics.timeout);
+        let idle_timeout = self.config.dynamic_streams.idle_timeout;
+        let mut idle_check = interval(idle_timeout.unwrap_or(DEFAULT_TIMEOUT));
+        let mut held_retry = interval(Duration::from_secs(1));
 
         loop {
             select! {
                 data = self.data_rx.recv_async() => {
                     let data = data?;
-                    self.streams.forward(data).await;
+                    // Discarded input is aggregated as received, without transforms, schema and deadband
+                    if !self.aggregations.add(&data) {
+                        self.streams.forward(data).await;
+                    }
+                }
+                // Forward aggregates of windows that close
+                Some(derived_stream) = self.aggregations.timeouts.next(), if self.aggregations.timeouts.has_pending() => {
+                    if let Some(data) = self.aggregations.flush(&derived_stream) {
+                        self.streams.forward(data).await;
+                    }
                 }
                 // Flush streams that timeout
                 Some(timedout_stream) = self.streams.stream_timeouts.next(), if self.streams.stream_timeouts.has_pending() => {
@@ -68,6 +95,14 @@ impl DataBridge {
                         error!("Failed to flush stream = {timedout_stream}. Error = {e}");
                     }
                 }
+                // Send data held back while the serializer was busy
+                _ = held_retry.tick() => {
+                    self.streams.send_held().await;
+                }
+                // Evict dynamic streams that are idle
+                _ = idle_check.tick(), if idle_timeout.is_some() => {
+                    self.streams.evict_idle(idle_timeout.unwrap()).await;
+                }
                 // Flush all metrics when timed out
                 _ = metrics_timeout.tick() => {
                     if let Err(e) = self.streams.check_and_flush_metrics() {
@@ -76,6 +111,9 @@ impl DataBridge {
                 }
                 // Handle a shutdown signal
                 _ = self.ctrl_rx.recv_async() => {
+                    for data in self.aggregations.flush_all() {
+                        self.streams.forward(data).await;
+                    }
                     self.streams.flush_all().await;
 
                     return Ok(())
@@ -0,0 +1,64 @@
+use serde_json::Value;
+
+use crate::config::{Deadband, Threshold};
+
+impl Threshold {
+    /// Change from `last` to `value` is beyond the threshold
+    pub fn exceeded(&self, last: f64, value: f64) -> bool {
+        let change = (value - last).abs();
+        match self {
+            Threshold::Absolute(threshold) => change > *threshold,
+            Threshold::Percent(percent) => change > last.abs() * percent / 100.0,
+        }
+    }
+}
+
+/// Filters out data points of a stream that don't differ meaningfully from the last forwarded one
+#[derive(Debug)]
+pub struct DeadbandFilter {
+    config: Deadband,
+    /// Timestamp and fields of the last forwarded data point
+    last: Option<(u64, Value)>,
+}
+
+impl DeadbandFilter {
+    pub fn new(config: Deadband) -> Self {
+        Self { config, last: None }
+    }
+
+    /// Returns true if the data point is to be forwarded, i.e. it is the first data point, the
+    /// heartbeat elapsed since the last forwarded point or any of its fields changed beyond deadband
+    pub fn pass(&mut self, timestamp: u64, fields: &Value) -> bool {
+        let forward = match &self.last {
+            None => true,
+            Some((last_timestamp, last)) => {
+                self.config.heartbeat.is_some_and(|heartbeat| {
+                    timestamp.saturating_sub(*last_timestamp) >= heartbeat.as_millis() as u64
+                }) || self.changed(last, fields)
+            }
+        };
+        if forward {
+            self.last = Some((timestamp, fields.clone()));
+        }
+
+        forward
+    }
+
+    // Fields added or removed are also considered as changes
+    fn changed(&self, last: &Value, fields: &Value) -> bool {
+        let (Value::Object(last), Value::Object(fields)) = (last, fields) else {
+            return last != fields;
+        };
+        if last.len() != fields.len() {
+            return true;
+        }
+
+        fields.iter().any(|(name, value)| {
+            let Some(last) = last.get(name) else { return true };
+            match (self.config.fields.get(name), last.as_f64(), value.as_f64()) {
+                (Some(threshold), Some(last), Some(value)) => threshold.exceeded(last, value),
+                _ => value != last,
+            }
+        })
+    }
+}
@@ -18,6 +18,18 @@ pub struct StreamMetrics {
     pub min_batch_latency: u64,
     pub max_batch_latency: u64,
     pub average_batch_latency: u64,
+    /// Number of transforms that failed on data points of the stream
+    pub transform_errors: usize,
+    /// Number of data points that didn't match the stream's schema
+    pub schema_violations: usize,
+    /// Number of data points dropped for not differing from the last forwarded one
+    pub deadband_drops: usize,
+    /// Number of data points rejected as the stream couldn't be created, over the limit on dynamic streams
+    pub rejected_points: usize,
+    /// Number of data points dropped by the overflow policy, while the serializer was busy
+    pub overflow_drops: usize,
+    /// Number of batches written to disk, while the serializer was busy
+    pub spilled_batches: usize,
 }
 
 impl StreamMetrics {
@@ -34,6 +46,12 @@ impl StreamMetrics {
             average_batch_latency: 0,
             min_batch_latency: 0,
             max_batch_latency: 0,
+            transform_errors: 0,
+            schema_violations: 0,
+            deadband_drops: 0,
+            rejected_points: 0,
+            overflow_drops: 0,
+            spilled_batches: 0,
         }
     }
 
@@ -45,6 +63,16 @@ impl StreamMetrics {
         self.points
     }
 
+    /// Nothing was counted since metrics were last sent, not even data points that were dropped
+    pub fn is_empty(&self) -> bool {
+        self.points == 0
+            && self.transform_errors == 0
+            && self.schema_violations == 0
+            && self.deadband_drops == 0
+            && self.overflow_drops == 0
+            && self.spilled_batches == 0
+    }
+
     pub fn add_point(&mut self) {
         self.points += 1;
         if self.points == 1 {
@@ -62,6 +90,26 @@ impl StreamMetrics {
         self.average_batch_latency = self.total_latency / self.batches;
     }
 
+    pub fn increment_transform_errors(&mut self) {
+        self.transform_errors += 1;
+    }
+
+    pub fn increment_schema_violations(&mut self) {
+        self.schema_violations += 1;
+    }
+
+    pub fn increment_deadband_drops(&mut self) {
+        self.deadband_drops += 1;
+    }
+
+    pub fn add_overflow_drops(&mut self, count: usize) {
+        self.overflow_drops += count;
+    }
+
+    pub fn increment_spilled_batches(&mut self) {
+        self.spilled_batches += 1;
+    }
+
     pub fn prepare_next(&mut self) {
         self.timestamp = clock();
         self.sequence += 1;
@@ -73,5 +121,10 @@ impl StreamMetrics {
         self.min_batch_latency = 0;
         self.max_batch_latency = 0;
         self.average_batch_latency = 0;
+        self.transform_errors = 0;
+        self.schema_violations = 0;
+        self.deadband_drops = 0;
+        self.overflow_drops = 0;
+        self.spilled_batches = 0;
     }
 }
@@ -5,33 +5,61 @@ use serde_json::Value;
 use std::{fmt::Debug, sync::Arc};
 
 mod actions_lane;
+mod aggregation;
+mod columnar;
 mod data_lane;
+mod deadband;
 mod delaymap;
 mod metrics;
+mod schema;
+mod spill;
 pub mod stream;
 mod streams;
+mod transform;
 
 pub use actions_lane::{ActionsBridge, Error};
 pub use actions_lane::{CtrlTx as ActionsLaneCtrlTx, StatusTx};
-use data_lane::DataBridge;
-pub use data_lane::{CtrlTx as DataLaneCtrlTx, DataTx};
+pub use aggregation::Aggregations;
+pub use columnar::Columns;
+pub use data_lane::{CtrlTx as DataLaneCtrlTx, DataBridge, DataTx};
+pub use deadband::DeadbandFilter;
 
 use crate::config::{ActionRoute, StreamConfig};
 use crate::{Action, ActionResponse, Config};
 pub use metrics::StreamMetrics;
+pub use schema::{Schema, SchemaError};
+pub use streams::DynamicStreams;
+pub use transform::TransformError;
 
 pub trait Point: Send + Debug + Serialize + 'static {
     fn stream_name(&self) -> &str;
     fn sequence(&self) -> u32;
     fn timestamp(&self) -> u64;
+    /// Fields of the point that stream transforms are applied on
+    fn fields_mut(&mut self) -> Option<&mut Value> {
+        None
+    }
+    /// Moves the point onto another stream, e.g. to quarantine invalid data
+    fn set_stream_name(&mut self, _name: String) {}
+}
+
+/// Errors faced while serializing a batch of data points in the stream's encoding
+#[derive(Debug, thiserror::Error)]
+pub enum EncodeError {
+    #[error("Json error {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Cbor error {0}")]
+    Cbor(#[from] ciborium::ser::Error<std::io::Error>),
+    #[error("MessagePack error {0}")]
+    MessagePack(#[from] rmp_serde::encode::Error),
 }
 
 pub trait Package: Send + Debug {
     fn stream_config(&self) -> Arc<StreamConfig>;
     fn stream_name(&self) -> Arc<String>;
-    // TODO: Implement a generic Return type that can wrap
-    // around custom serialization error types.
-    fn serialize(&self) -> serde_json::Result<Vec<u8>>;
+    fn serialize(&self) -> Result<Vec<u8>, EncodeError>;
+    /// Splits the batch into two halves, for batches too large to be sent in one publish
+    fn split(self: Box<Self>) -> (Box<dyn Package>, Box<dyn Package>);
     fn anomalies(&self) -> Option<(String, usize)>;
     fn len(&self) -> usize;
     fn latency(&self) -> u64;
@@ -64,6 +92,14 @@ impl Point for Payload {
     fn timestamp(&self) -> u64 {
         self.timestamp
     }
+
+    fn fields_mut(&mut self) -> Option<&mut Value> {
+        Some(&mut self.payload)
+    }
+
+    fn set_stream_name(&mut self, name: String) {
+        self.stream = name;
+    }
 }
 
 /// Commands that can be used to remotely trigger action_lane shutdown
@@ -96,6 +132,11 @@ impl Bridge {
         BridgeTx { data_tx: self.data.data_tx(), status_tx: self.actions.status_tx() }
     }
 
+    /// Handle to register streams that aren't configured, before sending their data
+    pub fn dynamic_streams(&self) -> DynamicStreams {
+        self.data.dynamic_streams()
+    }
+
     pub(crate) fn ctrl_tx(&self) -> (actions_lane::CtrlTx, data_lane::CtrlTx) {
         (self.actions.ctrl_tx(), self.data.ctrl_tx())
     }
@@ -0,0 +1,56 @@
+use std::sync::Arc;
+
+use jsonschema::Validator;
+use serde_json::Value;
+
+use crate::config::{OnInvalid, SchemaConfig};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SchemaError {
+    #[error("Io error {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Json error {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Invalid schema: {0}")]
+    Invalid(String),
+}
+
+/// Compiled JSON Schema that data points of a stream are validated against
+#[derive(Debug, Clone)]
+pub struct Schema {
+    /// Error of loading the schema, if it couldn't be loaded, every data point is then invalid
+    validator: Result<Arc<Validator>, Arc<String>>,
+    pub on_invalid: OnInvalid,
+}
+
+impl Schema {
+    pub fn new(schema: &Value, on_invalid: OnInvalid) -> Result<Self, SchemaError> {
+        let validator =
+            jsonschema::validator_for(schema).map_err(|e| SchemaError::Invalid(e.to_string()))?;
+
+        Ok(Self { validator: Ok(Arc::new(validator)), on_invalid })
+    }
+
+    /// Schema that couldn't be loaded, data points are handled as invalid as per `on_invalid`,
+    /// instead of being forwarded without validation
+    pub fn unloaded(error: SchemaError, on_invalid: OnInvalid) -> Self {
+        Self { validator: Err(Arc::new(error.to_string())), on_invalid }
+    }
+
+    /// Reads and compiles schema from the configured file
+    pub fn load(config: &SchemaConfig) -> Result<Self, SchemaError> {
+        let schema = serde_json::from_slice(&std::fs::read(&config.path)?)?;
+
+        Self::new(&schema, config.on_invalid)
+    }
+
+    /// Returns the first violation of schema in fields of a data point, if any
+    pub fn validate(&self, fields: &Value) -> Result<(), String> {
+        let validator = match &self.validator {
+            Ok(validator) => validator,
+            Err(e) => return Err(format!("schema couldn't be loaded: {e}")),
+        };
+
+        validator.validate(fields).map_err(|e| format!("{e} at {:?}", e.instance_path.to_string()))
+    }
+}
@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+
+use super::Point;
+
+/// Data point read back from a spilled batch of a stream, serialized as it was spilled
+#[derive(Debug)]
+pub struct SpilledPoint {
+    pub stream: Arc<String>,
+    pub point: Value,
+}
+
+impl Serialize for SpilledPoint {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.point.serialize(serializer)
+    }
+}
+
+impl Point for SpilledPoint {
+    fn stream_name(&self) -> &str {
+        &self.stream
+    }
+
+    fn sequence(&self) -> u32 {
+        self.point["sequence"].as_u64().unwrap_or_default() as u32
+    }
+
+    fn timestamp(&self) -> u64 {
+        self.point["timestamp"].as_u64().unwrap_or_default()
+    }
+}
+
+/// Batches of a stream written to disk while the serializer is busy, a file per batch holding
+/// a json array of its data points. Files are named by an increasing id, to be read oldest first.
+#[derive(Debug)]
+pub struct Spill {
+    dir: PathBuf,
+    /// Ids of spilled batches, oldest first
+    files: VecDeque<u64>,
+    next: u64,
+}
+
+impl Spill {
+    /// Picks up batches spilled before a restart
+    pub fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut files = vec![];
+        for entry in fs::read_dir(&dir)? {
+            let name = entry?.file_name();
+            if let Some(id) = name.to_str().and_then(|n| n.parse::<u64>().ok()) {
+                files.push(id);
+            }
+        }
+        files.sort_unstable();
+        let next = files.last().map_or(0, |id| id + 1);
+
+        Ok(Self { dir, files: files.into(), next })
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn write<T: Serialize>(&mut self, points: &[T]) -> io::Result<()> {
+        let batch = serde_json::to_vec(points)?;
+        fs::write(self.dir.join(self.next.to_string()), batch)?;
+        self.files.push_back(self.next);
+        self.next += 1;
+
+        Ok(())
+    }
+
+    /// Removes the oldest batch from disk, returning its data points
+    pub fn pop(&mut self) -> io::Result<Option<Vec<Value>>> {
+        let Some(id) = self.files.pop_front() else { return Ok(None) };
+        let path = self.dir.join(id.to_string());
+        let batch = fs::read(&path)?;
+        fs::remove_file(&path)?;
+
+        Ok(Some(serde_json::from_slice(&batch)?))
+    }
+}
@@ -1,11 +1,15 @@
-use std::{fmt::Debug, mem, sync::Arc, time::Duration};
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use std::{fmt::Debug, mem, sync::Arc};
 
 use flume::{SendError, Sender};
-use log::{debug, trace};
+use log::{debug, error, trace};
 use serde::Serialize;
 
-use super::{Package, Point, StreamMetrics};
-use crate::config::StreamConfig;
+use super::spill::{Spill, SpilledPoint};
+use super::{Columns, DeadbandFilter, EncodeError, Package, Point, Schema, StreamMetrics};
+use crate::config::{Encoding, OverflowPolicy, StreamConfig};
 
 /// Signals status of stream buffer
 #[derive(Debug)]
@@ -13,6 +17,9 @@ pub enum StreamStatus {
     Partial(usize),
     Flushed,
     Init(Duration),
+    /// Buffer was flushed ahead of a data point that would take it over `max_batch_bytes`,
+    /// the data point starts the next buffer
+    Rollover(Duration),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -29,9 +36,21 @@ pub struct Stream<T> {
     pub config: Arc<StreamConfig>,
     last_sequence: u32,
     last_timestamp: u64,
+    /// Time at which the stream last received data
+    last_fill: Instant,
     buffer: Buffer<T>,
+    /// Estimated serialized size of data points in buffer, tracked if `max_batch_bytes` is set
+    buffer_bytes: usize,
     tx: Sender<Box<dyn Package>>,
     pub metrics: StreamMetrics,
+    /// Schema that data points are validated against
+    pub schema: Option<Schema>,
+    /// Last forwarded data point, to drop points within deadband
+    pub deadband: Option<DeadbandFilter>,
+    /// Batches held back by the `drop_oldest` overflow policy, oldest first
+    held: VecDeque<Buffer<T>>,
+    /// Batches written to disk by the `spill_to_disk` overflow policy
+    spill: Option<Spill>,
 }
 
 impl<T> Stream<T>
@@ -48,28 +67,75 @@ where
         let config = Arc::new(stream_config);
         let buffer = Buffer::new(name.clone(), config.clone());
         let metrics = StreamMetrics::new(&name, config.batch_size);
+        let deadband = config.deadband.clone().map(DeadbandFilter::new);
+        // Data is handled as invalid if the schema couldn't be loaded
+        let schema = config.schema.as_ref().map(|schema| match Schema::load(schema) {
+            Ok(schema) => schema,
+            Err(e) => {
+                error!("Failed to load schema of stream: {name}; Error = {e}");
+                Schema::unloaded(e, schema.on_invalid)
+            }
+        });
 
-        Stream { name, config, last_sequence: 0, last_timestamp: 0, buffer, tx, metrics }
+        Stream {
+            name,
+            config,
+            last_sequence: 0,
+            last_timestamp: 0,
+            last_fill: Instant::now(),
+            buffer,
+            buffer_bytes: 0,
+            tx,
+            metrics,
+            schema,
+            deadband,
+            held: VecDeque::new(),
+            spill: None,
+        }
+    }
+
+    /// Sets the directory that batches are spilled into, batches already in it are sent first
+    pub fn set_spill_dir(&mut self, dir: PathBuf) {
+        match Spill::new(dir) {
+            Ok(spill) => self.spill = Some(spill),
+            Err(e) => error!("Failed to setup spill of stream: {}; Error = {e}", self.name),
+        }
     }
 
     pub fn dynamic(
         stream_name: impl Into<String>,
         project_id: impl Into<String>,
         device_id: impl Into<String>,
+        encoding: Encoding,
         tx: Sender<Box<dyn Package>>,
     ) -> Stream<T> {
         let stream_name = stream_name.into();
         let project_id = project_id.into();
         let device_id = device_id.into();
 
+        let suffix = encoding.topic_suffix();
         let topic =
-            format!("/tenants/{project_id}/devices/{device_id}/events/{stream_name}/jsonarray");
-        let config = StreamConfig { topic, ..Default::default() };
+            format!("/tenants/{project_id}/devices/{device_id}/events/{stream_name}/{suffix}");
+        let config = StreamConfig { topic, encoding, ..Default::default() };
 
         Stream::new(stream_name, config, tx)
     }
 
-    fn add(&mut self, data: T) -> Result<Option<Buffer<T>>, Error> {
+    fn add(&mut self, data: T) -> Result<Vec<Buffer<T>>, Error> {
+        let mut flushed = vec![];
+        let size = match self.config.max_batch_bytes {
+            Some(max_batch_bytes) => {
+                let size = estimate_size(&data);
+                // Flush buffer ahead of data that would take it over max_batch_bytes
+                if !self.is_empty() && self.buffer_bytes + size > max_batch_bytes {
+                    self.metrics.add_batch();
+                    flushed.push(self.take_buffer());
+                }
+                size
+            }
+            None => 0,
+        };
+
         let current_sequence = data.sequence();
         let current_timestamp = data.timestamp();
         let last_sequence = self.last_sequence;
@@ -77,6 +143,7 @@ where
 
         // Fill buffer with data
         self.buffer.buffer.push(data);
+        self.buffer_bytes += size;
         self.metrics.add_point();
 
         // Anomaly detection
@@ -93,15 +160,15 @@ where
         self.last_sequence = current_sequence;
         self.last_timestamp = current_timestamp;
 
-        // if max_bATCH_size is breached, flush
-        let buf = if self.buffer.buffer.len() >= self.config.batch_size {
+        // if max_bATCH_size or max_batch_bytes is breached, flush
+        if self.buffer.buffer.len() >= self.config.batch_size
+            || self.config.max_batch_bytes.is_some_and(|max| self.buffer_bytes >= max)
+        {
             self.metrics.add_batch();
-            Some(self.take_buffer())
-        } else {
-            None
-        };
+            flushed.push(self.take_buffer());
+        }
 
-        Ok(buf)
+        Ok(flushed)
     }
 
     // Returns buffer content, replacing with empty buffer in-place
@@ -109,6 +176,7 @@ where
         let name = self.name.clone();
         let config = self.config.clone();
         trace!("Flushing stream name: {name}, topic: {}", config.topic);
+        self.buffer_bytes = 0;
 
         mem::replace(&mut self.buffer, Buffer::new(name, config))
     }
@@ -117,33 +185,143 @@ where
     pub async fn flush(&mut self) -> Result<(), Error> {
         if !self.is_empty() {
             let buf = self.take_buffer();
+            self.send(buf).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends batch to the serializer, holding it back or dropping it as per the overflow policy,
+    /// if the serializer is busy
+    async fn send(&mut self, buf: Buffer<T>) -> Result<(), Error> {
+        let policy = self.config.overflow;
+        if policy != OverflowPolicy::Block {
+            self.send_held().await?;
+        }
+
+        let has_held = !self.held.is_empty() || self.spill.as_ref().is_some_and(|s| !s.is_empty());
+        if policy == OverflowPolicy::Block || (!has_held && !self.tx.is_full()) {
+            // NOTE: channel is shared with other lanes, this waits if it filled up in the meantime
+            self.tx.send_async(Box::new(buf)).await?;
+            return Ok(());
+        }
+
+        match (policy, self.spill.as_mut()) {
+            (OverflowPolicy::DropOldest, _) => {
+                self.held.push_back(buf);
+                while self.held.len() > self.config.overflow_capacity {
+                    let dropped = self.held.pop_front().unwrap();
+                    self.metrics.add_overflow_drops(dropped.buffer.len());
+                }
+            }
+            (OverflowPolicy::SpillToDisk, Some(spill)) => {
+                if let Err(e) = spill.write(&buf.buffer) {
+                    error!("Failed to spill batch of stream: {}; Error = {e}", self.name);
+                    self.metrics.add_overflow_drops(buf.buffer.len());
+                    return Ok(());
+                }
+                self.metrics.increment_spilled_batches();
+                while spill.len() > self.config.overflow_capacity {
+                    match spill.pop() {
+                        Ok(dropped) => {
+                            let count = dropped.map_or(0, |points| points.len());
+                            self.metrics.add_overflow_drops(count);
+                        }
+                        Err(e) => {
+                            error!(
+                                "Failed to drop spilled batch of stream: {}; Error = {e}",
+                                self.name
+                            );
+                            break;
+                        }
+                    }
+                }
+            }
+            // Spill couldn't be setup for `spill_to_disk`, data is dropped instead
+            _ => self.metrics.add_overflow_drops(buf.buffer.len()),
+        }
+
+        Ok(())
+    }
+
+    /// Sends batches held back by the overflow policy, oldest first, while the serializer has room
+    pub async fn send_held(&mut self) -> Result<(), Error> {
+        while !self.tx.is_full() {
+            if let Some(buf) = self.held.pop_front() {
+                self.tx.send_async(Box::new(buf)).await?;
+                continue;
+            }
+
+            let Some(buf) = self.pop_spilled() else { break };
             self.tx.send_async(Box::new(buf)).await?;
         }
 
         Ok(())
     }
 
+    /// Sends all batches held back by the overflow policy, oldest first, waiting for room in the
+    /// serializer. Used when the stream goes away, i.e. on eviction or shutdown.
+    pub async fn drain_held(&mut self) -> Result<(), Error> {
+        while let Some(buf) = self.held.pop_front() {
+            self.tx.send_async(Box::new(buf)).await?;
+        }
+        while let Some(buf) = self.pop_spilled() {
+            self.tx.send_async(Box::new(buf)).await?;
+        }
+
+        Ok(())
+    }
+
+    // Reads back the oldest spilled batch, skipping batches that couldn't be read
+    fn pop_spilled(&mut self) -> Option<Buffer<SpilledPoint>> {
+        let spill = self.spill.as_mut()?;
+        let points = loop {
+            match spill.pop() {
+                Ok(points) => break points?,
+                Err(e) => {
+                    error!("Failed to read spilled batch of stream: {}; Error = {e}", self.name)
+                }
+            }
+        };
+        let mut buf = Buffer::new(self.name.clone(), self.config.clone());
+        buf.buffer = points
+            .into_iter()
+            .map(|point| SpilledPoint { stream: self.name.clone(), point })
+            .collect();
+
+        Some(buf)
+    }
+
     /// Returns number of elements in Stream buffer
     pub fn len(&self) -> usize {
         self.buffer.buffer.len()
     }
 
+    /// Time since the stream last received data
+    pub fn idle_time(&self) -> Duration {
+        self.last_fill.elapsed()
+    }
+
     /// Check if Stream buffer is empty
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
-    /// Fill buffer with data and trigger async channel send on breaching max_batch_size.
+    /// Fill buffer with data and trigger async channel send on breaching max_batch_size or max_batch_bytes.
     /// Returns [`StreamStatus`].
     pub async fn fill(&mut self, data: T) -> Result<StreamStatus, Error> {
-        if let Some(buf) = self.add(data)? {
-            self.tx.send_async(Box::new(buf)).await?;
-            return Ok(StreamStatus::Flushed);
+        self.last_fill = Instant::now();
+        let flushed = self.add(data)?;
+        let rollover = !flushed.is_empty();
+        for buf in flushed {
+            self.send(buf).await?;
         }
 
-        let status = match self.len() {
-            1 => StreamStatus::Init(self.config.flush_period),
-            len => StreamStatus::Partial(len),
+        let status = match (rollover, self.len()) {
+            (true, 0) => StreamStatus::Flushed,
+            (true, _) => StreamStatus::Rollover(self.config.flush_period),
+            (false, 1) => StreamStatus::Init(self.config.flush_period),
+            (false, len) => StreamStatus::Partial(len),
         };
 
         Ok(status)
@@ -216,8 +394,26 @@ where
         self.stream_name.clone()
     }
 
-    fn serialize(&self) -> serde_json::Result<Vec<u8>> {
-        serde_json::to_vec(&self.buffer)
+    fn serialize(&self) -> Result<Vec<u8>, EncodeError> {
+        let encoding = self.stream_config.encoding;
+        if self.stream_config.columnar {
+            let columns = Columns::new(&self.buffer)?;
+            return encode(&columns, encoding);
+        }
+
+        encode(&self.buffer, encoding)
+    }
+
+    fn split(mut self: Box<Self>) -> (Box<dyn Package>, Box<dyn Package>) {
+        let second = Buffer {
+            buffer: self.buffer.split_off(self.buffer.len() / 2),
+            stream_name: self.stream_name.clone(),
+            stream_config: self.stream_config.clone(),
+            anomalies: String::new(),
+            anomaly_count: 0,
+        };
+
+        (self, Box::new(second))
     }
 
     fn anomalies(&self) -> Option<(String, usize)> {
@@ -233,6 +429,44 @@ where
     }
 }
 
+/// Counts bytes written, without holding them
+struct ByteCounter(usize);
+
+impl std::io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Size of data point serialized as json, along with a separator, is used as the estimate
+// in all encodings as it is cheap to compute and doesn't over estimate
+fn estimate_size<S: Serialize>(value: &S) -> usize {
+    let mut counter = ByteCounter(0);
+    match serde_json::to_writer(&mut counter, value) {
+        Ok(_) => counter.0 + 1,
+        Err(_) => 0,
+    }
+}
+
+fn encode<S: Serialize>(value: &S, encoding: Encoding) -> Result<Vec<u8>, EncodeError> {
+    let payload = match encoding {
+        Encoding::Json => serde_json::to_vec(value)?,
+        Encoding::Cbor => {
+            let mut payload = vec![];
+            ciborium::into_writer(value, &mut payload)?;
+            payload
+        }
+        Encoding::MessagePack => rmp_serde::to_vec_named(value)?,
+    };
+
+    Ok(payload)
+}
+
 impl<T> Clone for Stream<T> {
     fn clone(&self) -> Self {
         Stream {
@@ -240,9 +474,15 @@ impl<T> Clone for Stream<T> {
             config: self.config.clone(),
             last_sequence: 0,
             last_timestamp: 0,
+            last_fill: Instant::now(),
             buffer: Buffer::new(self.buffer.stream_name.clone(), self.buffer.stream_config.clone()),
+            buffer_bytes: 0,
             metrics: StreamMetrics::new(&self.name, self.config.batch_size),
             tx: self.tx.clone(),
+            schema: self.schema.clone(),
+            deadband: self.config.deadband.clone().map(DeadbandFilter::new),
+            held: VecDeque::new(),
+            spill: None,
         }
     }
 }
@@ -1,21 +1,87 @@
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use flume::Sender;
-use log::{error, info, trace};
+use log::{debug, error, info, trace};
+use serde_json::Value;
 
 use super::stream::{self, StreamStatus};
 use super::{Point, StreamMetrics};
-use crate::config::StreamConfig;
+use crate::config::{OnInvalid, OverflowPolicy, StreamConfig};
 use crate::{Config, Package, Stream};
 
 use super::delaymap::DelayMap;
 
+#[derive(Debug, Default)]
+struct Registry {
+    names: HashSet<String>,
+    /// Number of data points rejected for each stream that couldn't be created
+    rejected: HashMap<String, usize>,
+}
+
+/// Names of streams created dynamically, on receiving data. Handles are shared with
+/// collectors, so that data of streams over the limit can be rejected at the source.
+#[derive(Debug, Clone)]
+pub struct DynamicStreams {
+    config: Arc<Config>,
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl DynamicStreams {
+    pub fn new(config: Arc<Config>) -> Self {
+        Self { config, registry: Arc::default() }
+    }
+
+    pub fn max(&self) -> usize {
+        match self.config.simulator {
+            Some(_) => usize::MAX,
+            None => self.config.dynamic_streams.max,
+        }
+    }
+
+    /// Streams that are configured or derived by aggregations aren't limited, nor evicted
+    pub fn is_configured(&self, stream_name: &str) -> bool {
+        self.config.streams.contains_key(stream_name)
+            || self.config.aggregations.contains_key(stream_name)
+    }
+
+    /// Registers a stream that isn't configured, returns false and counts the data point
+    /// as rejected if the limit on dynamic streams is reached
+    pub fn register(&self, stream_name: &str) -> bool {
+        if self.is_configured(stream_name) {
+            return true;
+        }
+
+        let mut registry = self.registry.lock().unwrap();
+        if registry.names.contains(stream_name) {
+            return true;
+        }
+        if registry.names.len() >= self.max() {
+            *registry.rejected.entry(stream_name.to_owned()).or_default() += 1;
+            return false;
+        }
+        registry.names.insert(stream_name.to_owned());
+
+        true
+    }
+
+    pub fn remove(&self, stream_name: &str) {
+        self.registry.lock().unwrap().names.remove(stream_name);
+    }
+
+    /// Counts of data points rejected per stream, since the last call
+    pub fn take_rejected(&self) -> HashMap<String, usize> {
+        std::mem::take(&mut self.registry.lock().unwrap().rejected)
+    }
+}
+
 pub struct Streams<T> {
     config: Arc<Config>,
     data_tx: Sender<Box<dyn Package>>,
     metrics_tx: Sender<StreamMetrics>,
     map: HashMap<String, Stream<T>>,
+    dynamic_streams: DynamicStreams,
     pub stream_timeouts: DelayMap<String>,
 }
 
@@ -25,37 +91,127 @@ impl<T: Point> Streams<T> {
         data_tx: Sender<Box<dyn Package>>,
         metrics_tx: Sender<StreamMetrics>,
     ) -> Self {
-        Self { config, data_tx, metrics_tx, map: HashMap::new(), stream_timeouts: DelayMap::new() }
+        let dynamic_streams = DynamicStreams::new(config.clone());
+
+        Self {
+            config,
+            data_tx,
+            metrics_tx,
+            map: HashMap::new(),
+            dynamic_streams,
+            stream_timeouts: DelayMap::new(),
+        }
+    }
+
+    /// Handle to register dynamic streams
+    pub fn dynamic_streams(&self) -> DynamicStreams {
+        self.dynamic_streams.clone()
     }
 
     pub fn config_streams(&mut self, streams_config: HashMap<String, StreamConfig>) {
         for (name, stream) in streams_config {
-            let stream = Stream::new(&name, stream, self.data_tx.clone());
+            let mut stream = Stream::new(&name, stream, self.data_tx.clone());
+            if stream.config.overflow == OverflowPolicy::SpillToDisk {
+                stream.set_spill_dir(self.config.persistence_path.join("spill").join(&name));
+            }
+            self.map.insert(name.to_owned(), stream);
+        }
+    }
+
+    /// Creates derived streams that aren't configured, with defaults of dynamic streams
+    pub fn derived_streams<'a>(&mut self, names: impl IntoIterator<Item = &'a String>) {
+        for name in names {
+            if self.map.contains_key(name) {
+                continue;
+            }
+            let stream = Stream::dynamic(
+                name,
+                &self.config.project_id,
+                &self.config.device_id,
+                self.config.default_encoding,
+                self.data_tx.clone(),
+            );
             self.map.insert(name.to_owned(), stream);
         }
     }
 
-    pub async fn forward(&mut self, data: T) {
+    pub async fn forward(&mut self, mut data: T) {
         let stream_name = data.stream_name().to_string();
+        let Some(stream) = self.stream(&stream_name) else { return };
+
+        // Failed transforms leave the field as is, the data point is still forwarded
+        if let Some(fields) = data.fields_mut() {
+            for transform in stream.config.transforms.iter() {
+                if let Err(e) = transform.apply(fields) {
+                    debug!("Failed to transform data of stream: {stream_name}; Error = {e}");
+                    stream.metrics.increment_transform_errors();
+                }
+            }
+        }
 
-        // Create stream if it doesn't already exist
-        if !self.map.contains_key(&stream_name) {
-            if self.config.simulator.is_none() && self.map.keys().len() > 20 {
-                error!("Failed to create {:?} stream. More than max 20 streams", stream_name);
+        if let Some(schema) = stream.schema.as_ref() {
+            let on_invalid = schema.on_invalid;
+            if let Some(Err(e)) = data.fields_mut().map(|fields| schema.validate(fields)) {
+                debug!("Invalid data on stream: {stream_name}; Error = {e}");
+                stream.metrics.increment_schema_violations();
+                match on_invalid {
+                    OnInvalid::Drop => return,
+                    OnInvalid::Quarantine => {
+                        data.set_stream_name(format!("{stream_name}_rejected"));
+                        self.fill(data).await;
+                        return;
+                    }
+                    OnInvalid::Flag => {
+                        if let Some(Value::Object(fields)) = data.fields_mut() {
+                            fields.insert("_schema_error".to_owned(), Value::String(e));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Points that don't differ meaningfully from the last forwarded one are dropped
+        if let Some(filter) = stream.deadband.as_mut() {
+            let timestamp = data.timestamp();
+            if data.fields_mut().is_some_and(|fields| !filter.pass(timestamp, fields)) {
+                trace!("Dropping data within deadband of stream: {stream_name}");
+                stream.metrics.increment_deadband_drops();
                 return;
             }
+        }
+
+        self.fill(data).await;
+    }
+
+    // Returns the named stream, creating it if it doesn't already exist
+    fn stream(&mut self, stream_name: &str) -> Option<&mut Stream<T>> {
+        if !self.map.contains_key(stream_name) {
+            if !self.dynamic_streams.register(stream_name) {
+                error!(
+                    "Failed to create {stream_name:?} stream. More than max {} dynamic streams",
+                    self.dynamic_streams.max()
+                );
+                return None;
+            }
 
             let stream = Stream::dynamic(
-                &stream_name,
+                stream_name,
                 &self.config.project_id,
                 &self.config.device_id,
+                self.config.default_encoding,
                 self.data_tx.clone(),
             );
 
             self.map.insert(stream_name.to_owned(), stream);
         }
-        // Doesn't panic because of above check
-        let stream = self.map.get_mut(&stream_name).unwrap();
+
+        self.map.get_mut(stream_name)
+    }
+
+    // Adds data point into the buffer of its stream, as is
+    async fn fill(&mut self, data: T) {
+        let stream_name = data.stream_name().to_string();
+        let Some(stream) = self.stream(&stream_name) else { return };
 
         let max_stream_size = stream.config.batch_size;
         let state = match stream.fill(data).await {
@@ -76,18 +232,68 @@ impl<T: Point> Streams<T> {
                     trace!("Initialized stream buffer for {stream_name}");
                     self.stream_timeouts.insert(&stream_name, flush_period);
                 }
+                StreamStatus::Rollover(flush_period) => {
+                    self.stream_timeouts.remove(&stream_name);
+                    self.stream_timeouts.insert(&stream_name, flush_period);
+                }
                 StreamStatus::Partial(_l) => {}
             }
         }
     }
 
-    /// Flush all streams, use on bridge shutdown
+    /// Flushes and removes dynamic streams that haven't received data for `idle_timeout`
+    pub async fn evict_idle(&mut self, idle_timeout: Duration) {
+        let idle: Vec<String> = self
+            .map
+            .iter()
+            .filter(|(name, stream)| {
+                !self.dynamic_streams.is_configured(name) && stream.idle_time() >= idle_timeout
+            })
+            .map(|(name, _)| name.to_owned())
+            .collect();
+
+        for stream_name in idle {
+            // Doesn't panic as names were just collected from the map
+            let mut stream = self.map.remove(&stream_name).unwrap();
+            if !stream.is_empty() && stream.config.batch_size > 1 {
+                self.stream_timeouts.remove(&stream_name);
+            }
+            // Data held back by the overflow policy is sent along, as it is gone with the stream
+            if let Err(e) = stream.flush().await {
+                error!("Couldn't flush stream = {stream_name}; Error = {e}");
+            }
+            if let Err(e) = stream.drain_held().await {
+                error!("Couldn't send held back data of stream = {stream_name}; Error = {e}");
+            }
+            if !stream.metrics.is_empty() {
+                if let Err(e) = self.metrics_tx.try_send(stream.metrics) {
+                    debug!("Failed to flush metrics of stream = {stream_name}; Error = {e}");
+                }
+            }
+            self.dynamic_streams.remove(&stream_name);
+            info!("Evicted idle stream = {stream_name}");
+        }
+    }
+
+    /// Sends batches held back by overflow policies of streams, while the serializer has room
+    pub async fn send_held(&mut self) {
+        for (stream_name, stream) in self.map.iter_mut() {
+            if let Err(e) = stream.send_held().await {
+                error!("Couldn't send held back data of stream = {stream_name}; Error = {e}");
+            }
+        }
+    }
+
+    /// Flush all streams along with data held back by their overflow policies, use on bridge shutdown
     pub async fn flush_all(&mut self) {
         for (stream_name, stream) in self.map.iter_mut() {
             match stream.flush().await {
                 Err(e) => error!("Couldn't flush stream = {stream_name}; Error = {e}"),
                 _ => info!("Flushed stream = {stream_name}"),
             }
+            if let Err(e) = stream.drain_held().await {
+                error!("Couldn't send held back data of stream = {stream_name}; Error = {e}");
+            }
         }
     }
 
@@ -105,8 +311,8 @@ impl<T: Point> Streams<T> {
         for (buffer_name, data) in self.map.iter_mut() {
             let metrics = data.metrics.clone();
 
-            // Initialize metrics timeouts when force flush sees data counts
-            if metrics.points() > 0 {
+            // Initialize metrics timeouts when force flush sees data counts, also of dropped points
+            if !metrics.is_empty() {
                 info!(
                     "{buffer_name:>20}: points = {:<5} batches = {:<5} latency = {}",
                     metrics.points, metrics.batches, metrics.average_batch_latency
@@ -116,6 +322,14 @@ impl<T: Point> Streams<T> {
             }
         }
 
+        // Data points of streams that couldn't be created
+        for (stream_name, rejected) in self.dynamic_streams.take_rejected() {
+            info!("{stream_name:>20}: rejected = {rejected:<5}");
+            let mut metrics = StreamMetrics::new(&stream_name, 0);
+            metrics.rejected_points = rejected;
+            self.metrics_tx.try_send(metrics)?;
+        }
+
         Ok(())
     }
 }
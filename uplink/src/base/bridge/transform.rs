@@ -0,0 +1,73 @@
+use serde_json::{Number, Value};
+
+use crate::config::{CastType, Transform};
+
+#[derive(Debug, thiserror::Error)]
+pub enum TransformError {
+    #[error("Field {0:?} isn't a number")]
+    NotANumber(String),
+    #[error("Scaled value of field {0:?} isn't finite")]
+    NotFinite(String),
+    #[error("Couldn't cast field {0:?} into {1:?}")]
+    Cast(String, CastType),
+}
+
+impl Transform {
+    /// Applies the transform on fields of a data point, fields that are missing are left as is
+    pub fn apply(&self, payload: &mut Value) -> Result<(), TransformError> {
+        let Value::Object(fields) = payload else { return Ok(()) };
+
+        match self {
+            Transform::Drop { field } => {
+                fields.remove(field);
+            }
+            Transform::Rename { field, to } => {
+                if let Some(value) = fields.remove(field) {
+                    fields.insert(to.to_owned(), value);
+                }
+            }
+            Transform::Scale { field, factor, offset } => {
+                let Some(value) = fields.get_mut(field) else { return Ok(()) };
+                let n =
+                    value.as_f64().ok_or_else(|| TransformError::NotANumber(field.to_owned()))?;
+                let scaled = Number::from_f64(n * factor + offset)
+                    .ok_or_else(|| TransformError::NotFinite(field.to_owned()))?;
+                *value = Value::Number(scaled);
+            }
+            Transform::Cast { field, to } => {
+                let Some(value) = fields.get_mut(field) else { return Ok(()) };
+                *value =
+                    cast(value, *to).ok_or_else(|| TransformError::Cast(field.to_owned(), *to))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn cast(value: &Value, to: CastType) -> Option<Value> {
+    let value = match (to, value) {
+        (CastType::Number, Value::Number(_))
+        | (CastType::String, Value::String(_))
+        | (CastType::Boolean, Value::Bool(_)) => value.clone(),
+        (CastType::Number, Value::String(s)) => {
+            let s = s.trim();
+            match s.parse::<i64>() {
+                Ok(n) => Value::from(n),
+                Err(_) => Value::Number(Number::from_f64(s.parse().ok()?)?),
+            }
+        }
+        (CastType::Number, Value::Bool(b)) => Value::from(*b as u8),
+        (CastType::String, Value::Number(n)) => Value::String(n.to_string()),
+        (CastType::String, Value::Bool(b)) => Value::String(b.to_string()),
+        (CastType::Boolean, Value::Number(n)) => Value::Bool(n.as_f64()? != 0.0),
+        (CastType::Boolean, Value::String(s)) => match s.trim().to_lowercase().as_str() {
+            "true" | "1" => Value::Bool(true),
+            "false" | "0" => Value::Bool(false),
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    Some(value)
+}
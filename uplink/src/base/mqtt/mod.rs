@@ -3,12 +3,14 @@ use flume::{bounded, Receiver, Sender, TrySendError};
 use log::{debug, error, info};
 use storage::PersistenceFile;
 use thiserror::Error;
+use tokio::sync::Notify;
 use tokio::time::Duration;
 use tokio::{select, task};
 
 use std::fs::File;
 use std::io::Read;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 
 use crate::{Action, Config};
@@ -61,6 +63,8 @@ pub struct Mqtt {
     ctrl_tx: Sender<MqttShutdown>,
     /// True when network is connected
     network_up: Arc<Mutex<bool>>,
+    /// Tracks publishes acknowledged by the broker
+    acks: PublishAcks,
 }
 
 impl Mqtt {
@@ -86,6 +90,7 @@ impl Mqtt {
             ctrl_tx,
             ctrl_rx,
             network_up,
+            acks: PublishAcks::default(),
         }
     }
 
@@ -94,10 +99,38 @@ impl Mqtt {
         self.client.clone()
     }
 
+    /// Replaces an eventloop that has crashed with a new one, publishes pending in the crashed
+    /// eventloop are carried over. Clients of the crashed eventloop fail to send from here-on,
+    /// returns a client handle to the new eventloop.
+    pub fn recreate_eventloop(&mut self) -> AsyncClient {
+        let (client, mut eventloop) = AsyncClient::new(mqttoptions(&self.config), 0);
+        eventloop.network_options.set_connection_timeout(self.config.mqtt.network_timeout);
+
+        self.eventloop.clean();
+        eventloop.pending = std::mem::take(&mut self.eventloop.pending);
+        self.eventloop = eventloop;
+        self.client = client;
+
+        self.client()
+    }
+
     pub fn ctrl_tx(&self) -> CtrlTx {
         CtrlTx { inner: self.ctrl_tx.clone() }
     }
 
+    /// Returns a handle to track acknowledgements of publishes sent with the client
+    pub fn acks(&self) -> PublishAcks {
+        self.acks.clone()
+    }
+
+    /// Number of publishes that the eventloop has accepted, but aren't yet acknowledged
+    fn unacked(&self) -> usize {
+        let pending = self.eventloop.pending.iter().filter(|r| matches!(r, Request::Publish(_)));
+        let collision = self.eventloop.state.collision.is_some() as usize;
+
+        self.eventloop.state.inflight() as usize + pending.count() + collision
+    }
+
     /// Shutdown eventloop and write inflight publish packets to disk
     pub fn persist_inflight(&mut self) -> Result<(), Error> {
         self.eventloop.clean();
@@ -116,6 +149,9 @@ impl Mqtt {
         }
 
         let mut file = PersistenceFile::new(&self.config.persistence_path, "inflight".to_string())?;
+        if let Some(key) = self.config.persistence_key {
+            file.set_encryption_key(key);
+        }
         let mut buf = BytesMut::new();
 
         for publish in publishes {
@@ -133,6 +169,9 @@ impl Mqtt {
     fn reload_from_inflight_file(&mut self) -> Result<(), Error> {
         // Read contents of inflight file into an in-memory buffer
         let mut file = PersistenceFile::new(&self.config.persistence_path, "inflight".to_string())?;
+        if let Some(key) = self.config.persistence_key {
+            file.set_encryption_key(key);
+        }
         let path = file.path();
         if !path.is_file() {
             return Ok(());
@@ -163,7 +202,7 @@ impl Mqtt {
     }
 
     /// Poll eventloop to receive packets from broker
-    pub async fn start(mut self) {
+    pub async fn start(&mut self) {
         if let Err(e) = self.reload_from_inflight_file() {
             error!("Error recovering data from inflight file: {e}");
         }
@@ -198,7 +237,11 @@ impl Mqtt {
                         Ok(Event::Incoming(packet)) => {
                             debug!("Incoming = {:?}", packet);
                             match packet {
-                                rumqttc::Packet::PubAck(_) => self.metrics.add_puback(),
+                                // QoS 2 publishes leave inflight only once the handshake completes
+                                rumqttc::Packet::PubAck(_) | rumqttc::Packet::PubComp(_) => {
+                                    self.metrics.add_puback();
+                                    self.acks.update(self.unacked());
+                                }
                                 rumqttc::Packet::PingResp => {
                                     self.metrics.add_pingresp();
                                     let inflight = self.eventloop.state.inflight();
@@ -323,6 +366,55 @@ fn _get_certs(key_path: &Path, ca_path: &Path) -> (Vec<u8>, Vec<u8>) {
     (key, ca)
 }
 
+/// Counts publishes sent through the client and how many of them are acknowledged by the broker.
+///
+/// Brokers acknowledge QoS 1 publishes in the order they were sent, so when `n` publishes accepted
+/// by the eventloop are unacknowledged, all but the last `n` publishes sent are acknowledged.
+/// QoS 2 publishes are acknowledged on PUBCOMP, a QoS 2 publish counted as acknowledged by a later
+/// PUBACK has been received(PUBREC) by the broker already, as PUBRECs too are sent in order.
+/// Publishes of other clients in the eventloop are counted as unacknowledged, which only delays acks.
+#[derive(Debug, Clone, Default)]
+pub struct PublishAcks {
+    sent: Arc<AtomicUsize>,
+    acked: Arc<AtomicUsize>,
+    notify: Arc<Notify>,
+}
+
+impl PublishAcks {
+    /// Records a publish accepted by the eventloop, returns the sequence number upto which publishes
+    /// must be acknowledged for it to be delivered. QoS 0 publishes aren't acknowledged, so aren't counted.
+    pub fn sent(&self, qos: QoS) -> usize {
+        match qos {
+            QoS::AtMostOnce => self.sent.load(Ordering::SeqCst),
+            _ => self.sent.fetch_add(1, Ordering::SeqCst) + 1,
+        }
+    }
+
+    /// Sequence number upto which all publishes are acknowledged
+    pub fn acked(&self) -> usize {
+        self.acked.load(Ordering::SeqCst)
+    }
+
+    /// Waits until publishes upto sequence number `seq` are acknowledged
+    pub async fn wait_for_acked(&self, seq: usize) {
+        loop {
+            // NOTE: register for notification before checking, to not miss an update in between
+            let notified = self.notify.notified();
+            if self.acked() >= seq {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Records that all but the last `unacked` publishes sent are acknowledged
+    pub(crate) fn update(&self, unacked: usize) {
+        let acked = self.sent.load(Ordering::SeqCst).saturating_sub(unacked);
+        self.acked.fetch_max(acked, Ordering::SeqCst);
+        self.notify.notify_waiters();
+    }
+}
+
 /// Command to remotely trigger `Mqtt` shutdown
 pub(crate) struct MqttShutdown;
 
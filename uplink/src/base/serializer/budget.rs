@@ -0,0 +1,216 @@
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::config::DataBudget;
+use crate::Config;
+
+/// Bytes sent in the current day and month
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct Usage {
+    /// Days since the unix epoch
+    day: u64,
+    /// Months since the unix epoch
+    month: u64,
+    daily: usize,
+    monthly: usize,
+}
+
+impl Usage {
+    fn now() -> (u64, u64) {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let day = secs / 86400;
+
+        (day, month_of(day))
+    }
+}
+
+// Months since the unix epoch, of a day since the unix epoch.
+// Ref: https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn month_of(day: u64) -> u64 {
+    let z = day + 719468;
+    let era = z / 146097;
+    let doe = z % 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    (year - 1970) * 12 + month - 1
+}
+
+/// Tracks data sent against the configured daily and monthly budgets, usage is written to
+/// `budget` in persistence_path, to carry over across restarts.
+#[derive(Debug)]
+pub struct Budget {
+    config: Option<DataBudget>,
+    path: PathBuf,
+    usage: Usage,
+    /// Usage changed since it was last written to disk
+    dirty: bool,
+    /// Budget was exhausted since it was last replenished
+    was_exhausted: bool,
+}
+
+impl Budget {
+    pub fn new(config: &Config) -> Self {
+        let path = config.persistence_path.join("budget");
+        let mut budget = Budget {
+            config: config.budget.clone(),
+            path,
+            usage: Usage::default(),
+            dirty: false,
+            was_exhausted: false,
+        };
+        if budget.config.is_none() {
+            return budget;
+        }
+
+        match std::fs::read(&budget.path) {
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(usage) => budget.usage = usage,
+                Err(e) => {
+                    warn!(
+                        "Ignoring corrupted budget usage; path: {}; Error = {e}",
+                        budget.path.display()
+                    )
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => {
+                error!("Failed to read budget usage; path: {}; Error = {e}", budget.path.display())
+            }
+        }
+        budget.was_exhausted = budget.exhausted();
+        budget.roll_over();
+
+        budget
+    }
+
+    // Resets usage of the previous day or month
+    fn roll_over(&mut self) {
+        let (day, month) = Usage::now();
+        if self.usage.day != day {
+            self.usage.day = day;
+            self.usage.daily = 0;
+            self.dirty = true;
+        }
+        if self.usage.month != month {
+            self.usage.month = month;
+            self.usage.monthly = 0;
+            self.dirty = true;
+        }
+    }
+
+    /// Counts bytes sent against the budget
+    pub fn add(&mut self, size: usize) {
+        if self.config.is_none() {
+            return;
+        }
+
+        self.roll_over();
+        self.usage.daily += size;
+        self.usage.monthly += size;
+        self.dirty = true;
+        self.was_exhausted |= self.exhausted();
+    }
+
+    pub fn exhausted(&self) -> bool {
+        let Some(config) = &self.config else { return false };
+
+        config.daily.is_some_and(|daily| self.usage.daily >= daily)
+            || config.monthly.is_some_and(|monthly| self.usage.monthly >= monthly)
+    }
+
+    /// Data of a stream can be sent if the budget isn't exhausted or the stream is exempted from it
+    pub fn allows(&self, stream_name: &str, priority: u8) -> bool {
+        let Some(config) = &self.config else { return true };
+
+        stream_name == "action_status" || priority >= config.priority || !self.exhausted()
+    }
+
+    /// Rolls usage over to a new day or month, returns true if an exhausted budget was replenished.
+    /// Usage could also have been rolled over while counting data sent since the last refresh.
+    pub fn refresh(&mut self) -> bool {
+        self.roll_over();
+        if self.was_exhausted && !self.exhausted() {
+            self.was_exhausted = false;
+            info!("Data budget replenished");
+            return true;
+        }
+
+        false
+    }
+
+    /// Writes usage to disk, if it changed since the last write
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        let usage = serde_json::to_vec(&self.usage).expect("Usage should serialize");
+        // Written to a temporary file that replaces the previous one, to not lose usage on a crash midway
+        let tmp = self.path.with_extension("tmp");
+        if let Err(e) = std::fs::write(&tmp, usage).and_then(|_| std::fs::rename(&tmp, &self.path))
+        {
+            error!("Failed to write budget usage; path: {}; Error = {e}", self.path.display());
+            return;
+        }
+        self.dirty = false;
+    }
+
+    /// Bytes sent in the current day and month
+    pub fn usage(&self) -> (usize, usize) {
+        (self.usage.daily, self.usage.monthly)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    // Ensures that a replenished budget is noticed, even if data sent after midnight rolled it over
+    fn replenished_when_data_sent_rolls_usage_over() {
+        let dir = tempdir::TempDir::new("uplink-budget").unwrap();
+        let config = Config {
+            persistence_path: dir.path().to_owned(),
+            budget: Some(DataBudget { daily: Some(10), monthly: None, priority: 100 }),
+            ..Default::default()
+        };
+        let mut budget = Budget::new(&config);
+        budget.add(10);
+        assert!(budget.exhausted());
+        assert!(!budget.refresh());
+
+        // Data of an exempted stream is sent on the next day, before a refresh
+        budget.usage.day -= 1;
+        budget.add(5);
+        assert!(!budget.exhausted());
+        assert_eq!(budget.usage(), (5, 15));
+        assert!(budget.refresh());
+        assert!(!budget.refresh());
+    }
+
+    #[test]
+    // Ensures that usage loaded from disk over the budget is replenished on a new day
+    fn replenished_after_restart_on_a_new_day() {
+        let dir = tempdir::TempDir::new("uplink-budget").unwrap();
+        let config = Config {
+            persistence_path: dir.path().to_owned(),
+            budget: Some(DataBudget { daily: Some(10), monthly: None, priority: 100 }),
+            ..Default::default()
+        };
+        let mut budget = Budget::new(&config);
+        budget.add(10);
+        budget.usage.day -= 1;
+        budget.save();
+
+        let mut budget = Budget::new(&config);
+        assert!(!budget.exhausted());
+        assert!(budget.refresh());
+    }
+}
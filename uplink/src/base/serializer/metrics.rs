@@ -6,6 +6,7 @@ use serde_with::{serde_as, DurationSecondsWithFrac};
 use crate::base::clock;
 
 /// Metrics information relating to the operation of the `Serializer`, all values are reset on metrics flush
+#[serde_as]
 #[derive(Debug, Serialize, Clone)]
 pub struct Metrics {
     timestamp: u128,
@@ -24,10 +25,35 @@ pub struct Metrics {
     pub disk_utilized: usize,
     /// Nuber of persistence files that had to deleted before being consumed
     pub lost_segments: usize,
+    /// Number of persistence files deleted without being sent, as their data was older than `max_age`
+    pub expired_segments: usize,
+    /// Number of intact records recovered from torn persistence files
+    pub salvaged_records: usize,
+    /// Number of damaged records dropped from torn persistence files
+    pub dropped_records: usize,
+    /// Number of records dropped from storage for being larger than `mqtt.max_packet_size`
+    pub oversized_records: usize,
+    /// Size in bytes, of records dropped from storage for being larger than `mqtt.max_packet_size`,
+    /// these aren't counted in `sent_size`
+    pub oversized_size: usize,
     /// Number of errors faced during serializer operation
     pub errors: usize,
     /// Size in bytes, of serialized data sent onto network
     pub sent_size: usize,
+    /// Time spent in crash mode, since the mqtt eventloop crashed
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub crash_duration: Duration,
+    /// Number of batches held back to stay within rate limits
+    pub throttled: usize,
+    /// Time for which sending of data was delayed by rate limits
+    #[serde_as(as = "DurationSecondsWithFrac<f64>")]
+    pub throttle_delay: Duration,
+    /// Bytes sent today, counted against the data budget, not reset on flush
+    pub budget_daily: usize,
+    /// Bytes sent this month, counted against the data budget, not reset on flush
+    pub budget_monthly: usize,
+    /// Data budget is exhausted, only exempted streams are being sent
+    pub budget_exhausted: bool,
 }
 
 impl Metrics {
@@ -42,8 +68,19 @@ impl Metrics {
             disk_files: 0,
             disk_utilized: 0,
             lost_segments: 0,
+            expired_segments: 0,
+            salvaged_records: 0,
+            dropped_records: 0,
+            oversized_records: 0,
+            oversized_size: 0,
             errors: 0,
             sent_size: 0,
+            crash_duration: Duration::ZERO,
+            throttled: 0,
+            throttle_delay: Duration::ZERO,
+            budget_daily: 0,
+            budget_monthly: 0,
+            budget_exhausted: false,
         }
     }
 
@@ -86,10 +123,42 @@ impl Metrics {
         self.lost_segments += 1;
     }
 
+    pub fn add_expired_segments(&mut self, count: usize) {
+        self.expired_segments += count;
+    }
+
+    pub fn add_salvaged_records(&mut self, count: usize) {
+        self.salvaged_records += count;
+    }
+
+    pub fn add_dropped_records(&mut self, count: usize) {
+        self.dropped_records += count;
+    }
+
+    pub fn add_oversized_record(&mut self, size: usize) {
+        self.oversized_records += 1;
+        self.oversized_size += size;
+    }
+
     pub fn add_sent_size(&mut self, size: usize) {
         self.sent_size += size;
     }
 
+    pub fn set_crash_duration(&mut self, duration: Duration) {
+        self.crash_duration = duration;
+    }
+
+    pub fn add_throttled(&mut self, delay: Duration) {
+        self.throttled += 1;
+        self.throttle_delay += delay;
+    }
+
+    pub fn set_budget_usage(&mut self, daily: usize, monthly: usize, exhausted: bool) {
+        self.budget_daily = daily;
+        self.budget_monthly = monthly;
+        self.budget_exhausted = exhausted;
+    }
+
     pub fn prepare_next(&mut self) {
         self.timestamp = clock();
         self.sequence += 1;
@@ -98,8 +167,16 @@ impl Metrics {
         self.read_memory = 0;
         self.disk_files = 0;
         self.lost_segments = 0;
+        self.expired_segments = 0;
+        self.salvaged_records = 0;
+        self.dropped_records = 0;
+        self.oversized_records = 0;
+        self.oversized_size = 0;
         self.sent_size = 0;
         self.errors = 0;
+        self.crash_duration = Duration::ZERO;
+        self.throttled = 0;
+        self.throttle_delay = Duration::ZERO;
     }
 }
 
@@ -111,6 +188,15 @@ pub struct StreamMetrics {
     pub stream: String,
     pub serialized_data_size: usize,
     pub compressed_data_size: usize,
+    /// Ratio of serialized data size to its size after compression
+    pub compression_ratio: f64,
+    /// Number of segments of the stream deleted to stay within disk quota or `max_file_count`, or
+    /// dropped from memory on overflow without persistence
+    pub lost_segments: usize,
+    /// Number of times a batch was split in halves for being larger than `mqtt.max_packet_size`
+    pub splits: usize,
+    /// Number of data points dropped for being larger than `mqtt.max_packet_size` on their own
+    pub oversized_drops: usize,
     #[serde(skip)]
     pub serializations: u32,
     #[serde_as(as = "DurationSecondsWithFrac<f64>")]
@@ -133,6 +219,10 @@ impl StreamMetrics {
             sequence: 1,
             serialized_data_size: 0,
             compressed_data_size: 0,
+            compression_ratio: 0.0,
+            lost_segments: 0,
+            splits: 0,
+            oversized_drops: 0,
             serializations: 0,
             total_serialization_time: Duration::ZERO,
             avg_serialization_time: Duration::ZERO,
@@ -157,6 +247,18 @@ impl StreamMetrics {
         self.total_compression_time += compression_time;
     }
 
+    pub fn increment_lost_segments(&mut self) {
+        self.lost_segments += 1;
+    }
+
+    pub fn increment_splits(&mut self) {
+        self.splits += 1;
+    }
+
+    pub fn increment_oversized_drops(&mut self) {
+        self.oversized_drops += 1;
+    }
+
     // Should be called before serializing metrics to ensure averages are computed.
     // Averages aren't calculated for ever `add_*` call to save on costs.
     pub fn prepare_snapshot(&mut self) {
@@ -166,6 +268,10 @@ impl StreamMetrics {
             .unwrap_or(Duration::ZERO);
         self.avg_compression_time =
             self.total_compression_time.checked_div(self.compressions).unwrap_or(Duration::ZERO);
+        if self.compressed_data_size > 0 {
+            self.compression_ratio =
+                self.serialized_data_size as f64 / self.compressed_data_size as f64;
+        }
     }
 
     pub fn prepare_next(&mut self) {
@@ -173,6 +279,10 @@ impl StreamMetrics {
         self.sequence += 1;
         self.serialized_data_size = 0;
         self.compressed_data_size = 0;
+        self.compression_ratio = 0.0;
+        self.lost_segments = 0;
+        self.splits = 0;
+        self.oversized_drops = 0;
     }
 }
 
@@ -1,24 +1,37 @@
+mod budget;
 mod metrics;
+mod ratelimit;
 
 use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::{self, Write};
-use std::time::Instant;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{sync::Arc, time::Duration};
 
 use bytes::Bytes;
-use flume::{bounded, Receiver, RecvError, Sender, TrySendError};
-use log::{debug, error, info, trace};
+use flume::{bounded, unbounded, Receiver, RecvError, Sender, TrySendError};
+use log::{debug, error, info, trace, warn};
 use lz4_flex::frame::FrameEncoder;
 use rumqttc::*;
 use storage::Storage;
 use thiserror::Error;
-use tokio::{select, time::interval};
-
-use crate::config::{Compression, StreamConfig};
+use tokio::{
+    select,
+    time::{interval, interval_at},
+};
+use zstd::dict::EncoderDictionary;
+
+use crate::base::bridge::EncodeError;
+use crate::base::mqtt::PublishAcks;
+use crate::config::{CatchupOrder, Compression, RateLimit, StreamConfig};
 use crate::{Config, Package};
+use budget::Budget;
 pub use metrics::{Metrics, SerializerMetrics, StreamMetrics};
+use ratelimit::RateLimiter;
 
 const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval at which crash mode probes if the client accepts publishes again
+const CRASH_PROBE_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(thiserror::Error, Debug)]
 pub enum MqttError {
@@ -43,6 +56,8 @@ pub enum Error {
     Collector(#[from] RecvError),
     #[error("Serde error {0}")]
     Serde(#[from] serde_json::Error),
+    #[error("Encode error {0}")]
+    Encode(#[from] EncodeError),
     #[error("Io error {0}")]
     Io(#[from] io::Error),
     #[error("Storage error {0}")]
@@ -53,6 +68,8 @@ pub enum Error {
     MissingPersistence,
     #[error("LZ4 compression error: {0}")]
     Lz4(#[from] lz4_flex::frame::Error),
+    #[error("Couldn't load compression dictionary {0:?}, error = {1}")]
+    Dictionary(PathBuf, io::Error),
     #[error("Empty storage")]
     EmptyStorage,
     #[error("Permission denied while accessing persistence directory {0:?}")]
@@ -64,9 +81,9 @@ pub enum Error {
 #[derive(Debug, PartialEq)]
 enum Status {
     Normal,
-    SlowEventloop(Publish, Arc<StreamConfig>),
+    SlowEventloop(Publish, Arc<String>, Arc<StreamConfig>),
     EventLoopReady,
-    EventLoopCrash(Publish, Arc<StreamConfig>),
+    EventLoopCrash(Publish, Arc<String>, Arc<StreamConfig>),
     Shutdown,
 }
 
@@ -136,6 +153,10 @@ struct StorageHandler {
     map: BTreeMap<Arc<StreamConfig>, Storage>,
     // Stream being read from
     read_stream: Option<Arc<StreamConfig>>,
+    /// Publishes read from storage that are waiting on broker acks, as sequence number, stream and size
+    unacked: VecDeque<(usize, Arc<StreamConfig>, usize)>,
+    /// Publish read from storage that failed to be sent, as stream and size
+    unsent: Option<(Arc<StreamConfig>, usize)>,
 }
 
 impl StorageHandler {
@@ -145,8 +166,14 @@ impl StorageHandler {
         // NOTE: persist action_status if not configured otherwise
         streams.insert("action_status".into(), config.action_status.clone());
         for (stream_name, stream_config) in streams {
-            let mut storage =
-                Storage::new(&stream_config.topic, stream_config.persistence.max_file_size);
+            let mut storage = Storage::new(&stream_name, stream_config.persistence.max_file_size);
+            if stream_config.persistence.max_file_count == 0
+                && !stream_config.upload_windows.is_empty()
+            {
+                warn!(
+                    "Stream has upload windows without persistence, data held outside the windows is dropped on overflow: {stream_name}"
+                );
+            }
             if stream_config.persistence.max_file_count > 0 {
                 let mut path = config.persistence_path.clone();
                 path.push(&stream_name);
@@ -155,6 +182,27 @@ impl StorageHandler {
                     Error::Persistence(config.persistence_path.to_string_lossy().to_string())
                 })?;
                 storage.set_persistence(&path, stream_config.persistence.max_file_count)?;
+                match &stream_config.persistence.compression {
+                    Compression::Disabled => {}
+                    Compression::Lz4 => storage.set_compression(storage::Compression::Lz4),
+                    Compression::Zstd { level, dictionary } => {
+                        storage.set_compression(storage::Compression::Zstd(*level));
+                        if let Some(path) = dictionary {
+                            let dictionary = std::fs::read(path)
+                                .map_err(|e| Error::Dictionary(path.clone(), e))?;
+                            storage.set_zstd_dictionary(dictionary);
+                        }
+                    }
+                }
+                if let Some(key) = config.persistence_key {
+                    storage.set_encryption_key(key);
+                }
+                if config.persistence.delete_on_ack {
+                    storage.set_non_destructive_read(true);
+                }
+                if let CatchupOrder::NewestFirst = stream_config.catchup_order {
+                    storage.set_read_order(storage::ReadOrder::NewestFirst);
+                }
 
                 debug!(
                     "Disk persistance is enabled for stream: {stream_name:?}; path: {}",
@@ -164,25 +212,90 @@ impl StorageHandler {
             map.insert(Arc::new(stream_config.clone()), storage);
         }
 
-        Ok(Self { config, map, read_stream: None })
+        Ok(Self { config, map, read_stream: None, unacked: VecDeque::new(), unsent: None })
+    }
+
+    /// Tracks a publish of `len` bytes read from storage of stream, that was sent with sequence number `seq`
+    fn track(&mut self, seq: usize, stream: Arc<StreamConfig>, len: usize) {
+        if self.config.persistence.delete_on_ack {
+            self.unacked.push_back((seq, stream, len));
+        }
     }
 
-    fn select(&mut self, stream: &Arc<StreamConfig>) -> &mut Storage {
+    /// Writes committed read cursors of all storages to disk
+    fn save_cursors(&mut self) {
+        for storage in self.map.values_mut() {
+            if let Err(e) = storage.save_cursor() {
+                error!("Failed to save read cursor of storage = {}; error = {e}", storage.name());
+            }
+        }
+    }
+
+    /// Commits data of publishes upto sequence number `acked` as acknowledged in their storages
+    fn ack(&mut self, acked: usize) -> Result<(), storage::Error> {
+        let mut commits: BTreeMap<Arc<StreamConfig>, usize> = BTreeMap::new();
+        while let Some((seq, ..)) = self.unacked.front() {
+            if *seq > acked {
+                break;
+            }
+
+            let (_, stream, len) = self.unacked.pop_front().unwrap();
+            *commits.entry(stream).or_default() += len;
+        }
+
+        for (stream, len) in commits {
+            if let Some(storage) = self.map.get_mut(&stream) {
+                storage.ack(len)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Storage of a stream, named after the stream like storages of configured streams
+    fn select(&mut self, stream_name: &str, stream: &Arc<StreamConfig>) -> &mut Storage {
         self.map
             .entry(stream.to_owned())
-            .or_insert_with(|| Storage::new(&stream.topic, self.config.default_buf_size))
+            .or_insert_with(|| Storage::new(stream_name, self.config.default_buf_size))
     }
 
-    fn next(&mut self, metrics: &mut Metrics) -> Option<(&Arc<StreamConfig>, &mut Storage)> {
+    fn next(
+        &mut self,
+        metrics: &mut Metrics,
+        budget: &Budget,
+    ) -> Option<(&Arc<StreamConfig>, &mut Storage)> {
         let storages = self.map.iter_mut();
 
         for (stream, storage) in storages {
-            match (storage.reload_on_eof(), &mut self.read_stream) {
+            // Data of streams that are persist-only for now stays in storage
+            if is_persist_only(storage.name(), stream, budget) {
+                continue;
+            }
+
+            // Skip stale data before loading the next file to be read
+            if let Some(max_age) = stream.persistence.max_age {
+                if storage.inmemory_read_size() == 0 {
+                    match storage.delete_expired(max_age) {
+                        Ok(count) => metrics.add_expired_segments(count),
+                        Err(e) => {
+                            metrics.increment_errors();
+                            error!("Failed to delete expired files. Error = {e}");
+                        }
+                    }
+                }
+            }
+
+            let reload = storage.reload_on_eof();
+            let salvage = storage.take_salvage();
+            metrics.add_salvaged_records(salvage.salvaged);
+            metrics.add_dropped_records(salvage.dropped);
+
+            match (reload, &mut self.read_stream) {
                 // Done reading all pending files for a persisted stream
                 (Ok(true), Some(curr_stream)) => {
                     if curr_stream == stream {
                         self.read_stream.take();
-                        debug!("Completed reading from: {}", stream.topic);
+                        debug!("Completed reading from: {}", storage.name());
                     }
 
                     continue;
@@ -191,7 +304,7 @@ impl StorageHandler {
                 (Ok(true), _) => continue,
                 // Reading from a newly loaded non-empty persisted stream
                 (Ok(false), None) => {
-                    debug!("Reading from: {}", stream.topic);
+                    debug!("Reading from: {}", storage.name());
                     self.read_stream = Some(stream.to_owned());
                     return Some((stream, storage));
                 }
@@ -210,15 +323,64 @@ impl StorageHandler {
         None
     }
 
+    /// Streams with upload windows have data in storage that can now be sent
+    fn has_pending_upload(&self, budget: &Budget) -> bool {
+        self.map.iter().any(|(stream, storage)| {
+            !stream.upload_windows.is_empty()
+                && !is_persist_only(storage.name(), stream, budget)
+                && storage.inmemory_read_size()
+                    + storage.inmemory_write_size()
+                    + storage.file_count()
+                    > 0
+        })
+    }
+
+    /// Deletes oldest persistence files of the lowest priority streams first, until disk space
+    /// occupied by all streams is within the configured `persistence.max_disk_bytes`
+    fn enforce_disk_quota(
+        &mut self,
+        metrics: &mut Metrics,
+        stream_metrics: &mut HashMap<String, StreamMetrics>,
+    ) {
+        let Some(max_disk_bytes) = self.config.persistence.max_disk_bytes else {
+            return;
+        };
+        let mut disk_utilized: usize = self.map.values().map(|s| s.disk_utilized()).sum();
+
+        // NOTE: storages are ordered from highest to lowest priority
+        for storage in self.map.values_mut().rev() {
+            while disk_utilized > max_disk_bytes {
+                let size = storage.disk_utilized();
+                match storage.delete_oldest_file() {
+                    Ok(Some(deleted)) => debug!("Lost segment = {deleted}"),
+                    Ok(None) => break,
+                    Err(e) => {
+                        error!("Failed to free up disk space. Error = {e}");
+                        metrics.increment_errors();
+                        break;
+                    }
+                }
+                disk_utilized -= size - storage.disk_utilized();
+
+                let stream_name = storage.name();
+                metrics.increment_lost_segments();
+                stream_metrics
+                    .entry(stream_name.to_owned())
+                    .or_insert_with(|| StreamMetrics::new(stream_name))
+                    .increment_lost_segments();
+            }
+        }
+    }
+
     fn flush_all(&mut self) {
-        for (stream_config, storage) in self.map.iter_mut() {
+        self.save_cursors();
+        for storage in self.map.values_mut() {
             match storage.flush() {
-                Ok(_) => trace!("Force flushed stream = {} onto disk", stream_config.topic),
+                Ok(_) => trace!("Force flushed stream = {} onto disk", storage.name()),
                 Err(storage::Error::NoWrites) => {}
-                Err(e) => error!(
-                    "Error when force flushing storage = {}; error = {e}",
-                    stream_config.topic
-                ),
+                Err(e) => {
+                    error!("Error when force flushing storage = {}; error = {e}", storage.name())
+                }
             }
         }
     }
@@ -236,8 +398,10 @@ impl StorageHandler {
 /// the catchup mode.
 ///
 /// P.S: We have a transition into **crash mode** when we are in catchup or slow mode and the thread running the MQTT client
-/// stalls and dies out. Here we merely write all data received, directly into disk. This is a failure mode that ideally the
-/// serializer should never be operated in.
+/// stalls and dies out. Here we write all data received, directly into disk, until the client of a recreated eventloop is
+/// handed over with [`client_tx()`], to then retry the failed publish in **slow mode**. The failed publish is also retried
+/// periodically with [`try_publish()`], moving into **catchup mode** once the eventloop accepts it without being recreated.
+/// This is a failure mode that ideally the serializer should never be operated in.
 ///
 /// ```text
 ///
@@ -263,9 +427,10 @@ impl StorageHandler {
 ///
 ///```
 ///
-/// NOTE: Shutdown mode and crash mode are only different in how they get triggered,
-/// but should be considered as interchangeable in the above diagram.
+/// NOTE: Shutdown mode and crash mode are only different in how they get triggered and crash mode
+/// transitions into EventloopReady on recovery, but should be considered as interchangeable in the above diagram.
 /// [`start()`]: Serializer::start
+/// [`client_tx()`]: Serializer::client_tx
 /// [`try_publish()`]: AsyncClient::try_publish
 /// [`publish()`]: AsyncClient::publish
 pub struct Serializer<C: MqttClient> {
@@ -277,22 +442,39 @@ pub struct Serializer<C: MqttClient> {
     metrics_tx: Sender<SerializerMetrics>,
     pending_metrics: VecDeque<SerializerMetrics>,
     stream_metrics: HashMap<String, StreamMetrics>,
+    /// Zstd dictionaries of streams, prepared for compression
+    dictionaries: HashMap<String, EncoderDictionary<'static>>,
+    /// Acknowledgements of publishes sent with client
+    acks: PublishAcks,
+    /// Rate limits on data sent with client, shared with control handles
+    rate_limiter: RateLimiter,
+    /// Data sent against the daily and monthly budgets
+    budget: Budget,
     /// Control handles
     ctrl_rx: Receiver<SerializerShutdown>,
     ctrl_tx: Sender<SerializerShutdown>,
+    /// Clients of eventloops recreated after a crash
+    client_rx: Receiver<C>,
+    client_tx: Sender<C>,
 }
 
 impl<C: MqttClient> Serializer<C> {
     /// Construct the uplink Serializer with the necessary configuration details, a receiver handle to accept data payloads from,
-    /// the handle to an MQTT client(This is constructed as such for testing purposes) and a handle to update serailizer metrics.
+    /// the handle to an MQTT client(This is constructed as such for testing purposes), a handle to update serailizer metrics
+    /// and a handle to track acknowledgements of publishes sent with the client.
     pub fn new(
         config: Arc<Config>,
         collector_rx: Receiver<Box<dyn Package>>,
         client: C,
         metrics_tx: Sender<SerializerMetrics>,
+        acks: PublishAcks,
     ) -> Result<Serializer<C>, Error> {
         let storage_handler = StorageHandler::new(config.clone())?;
+        let dictionaries = load_dictionaries(&config)?;
+        let rate_limiter = RateLimiter::new(&config);
+        let budget = Budget::new(&config);
         let (ctrl_tx, ctrl_rx) = bounded(1);
+        let (client_tx, client_rx) = unbounded();
 
         Ok(Serializer {
             config,
@@ -301,15 +483,96 @@ impl<C: MqttClient> Serializer<C> {
             storage_handler,
             metrics: Metrics::new("catchup"),
             stream_metrics: HashMap::new(),
+            dictionaries,
             metrics_tx,
             pending_metrics: VecDeque::with_capacity(3),
+            acks,
+            rate_limiter,
+            budget,
             ctrl_tx,
             ctrl_rx,
+            client_tx,
+            client_rx,
         })
     }
 
     pub fn ctrl_tx(&self) -> CtrlTx {
-        CtrlTx { inner: self.ctrl_tx.clone() }
+        CtrlTx { inner: self.ctrl_tx.clone(), rate_limiter: self.rate_limiter.clone() }
+    }
+
+    /// Handle to hand over the client of an eventloop that was recreated after a crash,
+    /// for the serializer to recover from crash mode with
+    pub fn client_tx(&self) -> Sender<C> {
+        self.client_tx.clone()
+    }
+
+    /// Deletes data from storage once the broker has acknowledged receiving it
+    fn commit_acks(&mut self) {
+        if let Err(e) = self.storage_handler.ack(self.acks.acked()) {
+            error!("Failed to commit acknowledged data. Error = {e}");
+            self.metrics.increment_errors();
+        }
+    }
+
+    /// Tracks data of records dropped while reading from storage, to be committed along with
+    /// acknowledgements of publishes sent earlier, keeping acks aligned with data in storage
+    fn track_skipped(&mut self, stream: Arc<StreamConfig>, len: usize) {
+        let seq = self.acks.sent(QoS::AtMostOnce);
+        self.storage_handler.track(seq, stream, len);
+        self.commit_acks();
+    }
+
+    /// Writes publish to storage of the stream, within the disk quota
+    fn persist(&mut self, publish: Publish, stream_name: &str, stream: &Arc<StreamConfig>) {
+        let storage = self.storage_handler.select(stream_name, stream);
+        match write_to_storage(publish, storage) {
+            Ok(Some(deleted)) => {
+                debug!("Lost segment = {deleted}");
+                if stream.persistence.max_file_count == 0 && !stream.upload_windows.is_empty() {
+                    warn!("Dropped data of stream held outside upload windows, without persistence: {stream_name}");
+                }
+                self.metrics.increment_lost_segments();
+                self.stream_metrics
+                    .entry(stream_name.to_owned())
+                    .or_insert_with(|| StreamMetrics::new(stream_name))
+                    .increment_lost_segments();
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Storage write error = {e}");
+                self.metrics.increment_errors();
+            }
+        };
+        self.storage_handler.enforce_disk_quota(&mut self.metrics, &mut self.stream_metrics);
+    }
+
+    /// Counts bytes sent onto network in metrics and against the data budget
+    fn add_sent_size(&mut self, size: usize) {
+        self.metrics.add_sent_size(size);
+        self.budget.add(size);
+    }
+
+    /// Rolls data budget over to a new day or month, writes usage to disk and into metrics.
+    /// Returns true if an exhausted budget was replenished.
+    fn refresh_budget(&mut self) -> bool {
+        let replenished = self.budget.refresh();
+        self.budget.save();
+        let (daily, monthly) = self.budget.usage();
+        self.metrics.set_budget_usage(daily, monthly, self.budget.exhausted());
+
+        replenished
+    }
+
+    /// Number of publishes read from storage that can be inflight in catchup mode, while inflight
+    /// slots are left to data of streams that are sent live
+    fn backlog_limit(&self) -> usize {
+        match self.config.catchup.live_priority {
+            Some(_) => {
+                let max_inflight = self.config.mqtt.max_inflight as usize;
+                (max_inflight * self.config.catchup.backlog_share as usize / 100).max(1)
+            }
+            None => usize::MAX,
+        }
     }
 
     /// Write all data received, from here-on, to disk only, shutdown serializer
@@ -323,58 +586,125 @@ impl<C: MqttClient> Serializer<C> {
             let deadline = Instant::now() + Duration::from_secs(2);
             let Ok(data) = self.collector_rx.recv_deadline(deadline) else {
                 self.storage_handler.flush_all();
+                self.budget.save();
                 return Ok(());
             };
             let stream_config = data.stream_config();
-            let publish = construct_publish(data, &mut self.stream_metrics)?;
-            let storage = self.storage_handler.select(&stream_config);
-            match write_to_storage(publish, storage) {
-                Ok(Some(deleted)) => debug!("Lost segment = {deleted}"),
-                Ok(_) => {}
-                Err(e) => error!("Shutdown: write error = {e}"),
+            let stream_name = data.stream_name();
+            let max_packet_size = self.config.mqtt.max_packet_size;
+            let publishes = construct_publish(
+                data,
+                &mut self.stream_metrics,
+                &self.dictionaries,
+                max_packet_size,
+            )?;
+            for publish in publishes {
+                self.persist(publish, &stream_name, &stream_config);
             }
         }
     }
 
-    /// Write all data received, from here-on, to disk only.
+    /// Write all data received, from here-on, to disk only, until the client of a recreated
+    /// eventloop is handed over, to retry the failed publish with in slow mode. The failed publish
+    /// is also retried periodically with the current client, catching up once it is accepted.
     async fn crash(
         &mut self,
         publish: Publish,
+        stream_name: Arc<String>,
         stream: Arc<StreamConfig>,
     ) -> Result<Status, Error> {
-        let storage = self.storage_handler.select(&stream);
-        // Write failed publish to disk first, metrics don't matter
-        match write_to_storage(publish, storage) {
-            Ok(Some(deleted)) => debug!("Lost segment = {deleted}"),
-            Ok(_) => {}
-            Err(e) => error!("Crash loop: write error = {e}"),
-        }
+        let mut interval = interval(METRICS_INTERVAL);
+        // Reactlabs setup processes logs generated by uplink
+        info!("Switching to crash mode!!");
+        self.metrics.set_mode("crash");
+        let crashed_at = tokio::time::Instant::now();
+        let mut probe = interval_at(crashed_at + CRASH_PROBE_INTERVAL, CRASH_PROBE_INTERVAL);
+        let max_packet_size = self.config.mqtt.max_packet_size;
 
-        loop {
-            // Collect next data packet and write to disk
-            let data = self.collector_rx.recv_async().await?;
-            let publish = construct_publish(data, &mut self.stream_metrics)?;
-            let storage = self.storage_handler.select(&stream);
-            match write_to_storage(publish, storage) {
-                Ok(Some(deleted)) => debug!("Lost segment = {deleted}"),
-                Ok(_) => {}
-                Err(e) => error!("Crash loop: write error = {e}"),
+        let v: Result<Status, Error> = loop {
+            select! {
+                data = self.collector_rx.recv_async() => {
+                    // Collect next data packet and write to disk
+                    let data = data?;
+                    let stream = data.stream_config();
+                    let stream_name = data.stream_name();
+                    let publishes = construct_publish(data, &mut self.stream_metrics, &self.dictionaries, max_packet_size)?;
+                    for publish in publishes {
+                        self.persist(publish, &stream_name, &stream);
+                        // Update metrics
+                        self.metrics.add_batch();
+                    }
+                }
+                // Eventloop is back when the client of a recreated eventloop is handed over
+                Ok(client) = self.client_rx.recv_async() => {
+                    // Eventloop could have been recreated more than once, the latest client is used
+                    self.client = self.client_rx.try_iter().last().unwrap_or(client);
+                    info!("Recovered from crash after {}s", crashed_at.elapsed().as_secs());
+                    break Ok(Status::SlowEventloop(publish, stream_name, stream));
+                }
+                // Eventloop can recover without being recreated, e.g. after it stalled
+                _ = probe.tick() => {
+                    let Publish { topic, qos, retain, payload, .. } = publish.clone();
+                    if self.client.try_publish(topic, qos, retain, payload.to_vec()).is_err() {
+                        continue;
+                    }
+
+                    let seq = self.acks.sent(qos);
+                    self.add_sent_size(payload.len());
+                    // Failed publish of catchup, that was retried after a crash
+                    if let Some((stream, len)) = self.storage_handler.unsent.take() {
+                        self.storage_handler.track(seq, stream, len);
+                    }
+                    info!("Recovered from crash after {}s", crashed_at.elapsed().as_secs());
+                    break Ok(Status::EventLoopReady);
+                }
+                _ = interval.tick() => {
+                    self.metrics.set_crash_duration(crashed_at.elapsed());
+                    self.refresh_budget();
+                    let _ = check_and_flush_metrics(&mut self.pending_metrics, &mut self.metrics, &self.metrics_tx, &self.storage_handler);
+                }
+                // Write failed publish to disk and shutdown, when uplink is shutting down
+                Ok(SerializerShutdown) = self.ctrl_rx.recv_async() => {
+                    let storage = self.storage_handler.select(&stream_name, &stream);
+                    if let Err(e) = write_to_storage(publish, storage) {
+                        error!("Crash loop: write error = {e}");
+                    }
+                    break Ok(Status::Shutdown);
+                }
             }
-        }
+        };
+
+        self.metrics.set_crash_duration(crashed_at.elapsed());
+        save_and_prepare_next_metrics(
+            &mut self.pending_metrics,
+            &mut self.metrics,
+            &mut self.stream_metrics,
+            &self.storage_handler,
+        );
+
+        v
     }
 
     /// Write new data to disk until back pressure due to slow n/w is resolved
     // TODO: Handle errors. Don't return errors
-    async fn slow(&mut self, publish: Publish, stream: Arc<StreamConfig>) -> Result<Status, Error> {
+    async fn slow(
+        &mut self,
+        publish: Publish,
+        stream_name: Arc<String>,
+        stream: Arc<StreamConfig>,
+    ) -> Result<Status, Error> {
         let mut interval = interval(METRICS_INTERVAL);
         // Reactlabs setup processes logs generated by uplink
         info!("Switching to slow eventloop mode!!");
         self.metrics.set_mode("slow");
+        let max_packet_size = self.config.mqtt.max_packet_size;
 
         // Note: self.client.publish() is executing code before await point
         // in publish method every time. Verify this behaviour later
+        let qos = publish.qos;
+        let payload_size = publish.payload.len();
         let payload = Bytes::copy_from_slice(&publish.payload[..]);
-        let publish = send_publish(self.client.clone(), publish.topic, payload);
+        let publish = send_publish(self.client.clone(), Publish { payload, ..publish });
         tokio::pin!(publish);
 
         let v: Result<Status, Error> = loop {
@@ -382,31 +712,32 @@ impl<C: MqttClient> Serializer<C> {
                 data = self.collector_rx.recv_async() => {
                     let data = data?;
                     let stream = data.stream_config();
-                    let publish = construct_publish(data, &mut self.stream_metrics)?;
-                    let storage = self.storage_handler.select(&stream);
-                    match write_to_storage(publish, storage) {
-                        Ok(Some(deleted)) => {
-                            debug!("Lost segment = {deleted}");
-                            self.metrics.increment_lost_segments();
-                        }
-                        Ok(_) => {},
-                        Err(e) => {
-                            error!("Storage write error = {e}");
-                            self.metrics.increment_errors();
-                        }
-                    };
-
-                    // Update metrics
-                    self.metrics.add_batch();
+                    let stream_name = data.stream_name();
+                    let publishes = construct_publish(data, &mut self.stream_metrics, &self.dictionaries, max_packet_size)?;
+                    for publish in publishes {
+                        self.persist(publish, &stream_name, &stream);
+                        // Update metrics
+                        self.metrics.add_batch();
+                    }
                 }
                 o = &mut publish => match o {
-                    Ok(_) => break Ok(Status::EventLoopReady),
+                    Ok(_) => {
+                        let seq = self.acks.sent(qos);
+                        self.add_sent_size(payload_size);
+                        // Failed publish of catchup, that was retried after a crash
+                        if let Some((stream, len)) = self.storage_handler.unsent.take() {
+                            self.storage_handler.track(seq, stream, len);
+                        }
+                        break Ok(Status::EventLoopReady)
+                    }
                     Err(MqttError::Send(Request::Publish(publish))) => {
-                        break Ok(Status::EventLoopCrash(publish, stream));
+                        break Ok(Status::EventLoopCrash(publish, stream_name, stream));
                     }
                     Err(e) => unreachable!("Unexpected error: {e}"),
                 },
                 _ = interval.tick() => {
+                    self.commit_acks();
+                    self.storage_handler.save_cursors();
                     check_metrics(&mut self.metrics, &mut self.stream_metrics, &self.storage_handler);
                 }
                 // Transition into crash mode when uplink is shutting down
@@ -430,7 +761,8 @@ impl<C: MqttClient> Serializer<C> {
     /// disk to mqtt eventloop. Collector rx is selected with blocking
     /// `publish` instead of `try publish` to ensure that transient back
     /// pressure due to a lot of data on disk doesn't switch state to
-    /// `Status::SlowEventLoop`
+    /// `Status::SlowEventLoop`. Data of streams at or above `catchup.live_priority`
+    /// is published as it arrives, while the backlog is limited to a share of inflight.
     async fn catchup(&mut self) -> Result<Status, Error> {
         // Reactlabs setup processes logs generated by uplink
         info!("Switching to catchup mode!!");
@@ -438,17 +770,25 @@ impl<C: MqttClient> Serializer<C> {
         self.metrics.set_mode("catchup");
 
         let max_packet_size = self.config.mqtt.max_packet_size;
+        let backlog_limit = self.backlog_limit();
+        // Sequence numbers of publishes read from storage, that may not be acknowledged yet
+        let mut backlog_inflight = VecDeque::new();
         let client = self.client.clone();
 
-        let Some((stream, storage)) = self.storage_handler.next(&mut self.metrics) else {
+        let Some((stream, storage)) = self.storage_handler.next(&mut self.metrics, &self.budget)
+        else {
             return Ok(Status::Normal);
         };
 
-        // TODO(RT): This can fail when packet sizes > max_payload_size in config are written to disk.
-        // This leads to force switching to normal mode. Increasing max_payload_size to bypass this
-        let publish = match Packet::read(storage.reader(), max_packet_size) {
-            Ok(Packet::Publish(publish)) => publish,
-            Ok(packet) => unreachable!("Unexpected packet: {:?}", packet),
+        let unread = storage.reader().len();
+        let publish = match read_publish(storage, max_packet_size, &mut self.metrics) {
+            Ok(Some(publish)) => publish,
+            // Only oversized records were left in the reader, continue with the next
+            Ok(None) => {
+                let (stream, len) = (stream.clone(), unread - storage.reader().len());
+                self.track_skipped(stream, len);
+                return Ok(Status::EventLoopReady);
+            }
             Err(e) => {
                 self.metrics.increment_errors();
                 error!("Failed to read from storage. Forcing into Normal mode. Error = {e}");
@@ -463,8 +803,15 @@ impl<C: MqttClient> Serializer<C> {
         };
 
         let mut last_publish_payload_size = publish.payload.len();
+        let mut last_publish_len = unread - storage.reader().len();
         let mut last_publish_stream = stream.clone();
-        let send = send_publish(client, publish.topic, publish.payload);
+        let mut last_publish_stream_name = Arc::new(storage.name().to_owned());
+        let mut last_publish_qos = publish.qos;
+        let acks = self.acks.clone();
+        let seq = backlog_ack_needed(&mut backlog_inflight, acks.acked(), backlog_limit);
+        let limiter = self.rate_limiter.clone();
+        let send =
+            send_bounded(client, publish, acks, seq, limiter, last_publish_stream_name.clone());
         tokio::pin!(send);
 
         let v: Result<Status, Error> = loop {
@@ -472,40 +819,75 @@ impl<C: MqttClient> Serializer<C> {
                 data = self.collector_rx.recv_async() => {
                     let data = data?;
                     let stream = data.stream_config();
-                    let publish = construct_publish(data, &mut self.stream_metrics)?;
-                    let storage = self.storage_handler.select(&stream);
-                    match write_to_storage(publish, storage) {
-                        Ok(Some(deleted)) => {
-                            debug!("Lost segment = {deleted}");
-                            self.metrics.increment_lost_segments();
-                        }
-                        Ok(_) => {},
-                        Err(e) => {
-                            error!("Storage write error = {e}");
-                            self.metrics.increment_errors();
-                        }
-                    };
-
-                    // Update metrics
-                    self.metrics.add_batch();
+                    let stream_name = data.stream_name();
+                    let publishes = construct_publish(data, &mut self.stream_metrics, &self.dictionaries, max_packet_size)?;
+                    for publish in publishes {
+                        // Data of high priority streams is sent right away, instead of after the backlog,
+                        // unless held back by rate limits. Tokens are taken only once the data is sent.
+                        let publish = match self.config.catchup.live_priority {
+                            Some(live_priority) if stream.priority >= live_priority
+                                && !is_persist_only(&stream_name, &stream, &self.budget)
+                                && self.rate_limiter.available(&stream_name, publish.payload.len()) => {
+                                let payload_size = publish.payload.len();
+                                let qos = to_qos(stream.qos);
+                                match self.client.try_publish(&stream.topic, qos, stream.retain, publish.payload) {
+                                    Ok(_) => {
+                                        self.rate_limiter.take(&stream_name, payload_size);
+                                        self.acks.sent(qos);
+                                        self.metrics.add_batch();
+                                        self.add_sent_size(payload_size);
+                                        continue;
+                                    }
+                                    Err(MqttError::TrySend(Request::Publish(publish))) => publish,
+                                    Err(e) => unreachable!("Unexpected error: {e}"),
+                                }
+                            }
+                            _ => publish,
+                        };
+                        self.persist(publish, &stream_name, &stream);
+
+                        // Update metrics
+                        self.metrics.add_batch();
+                    }
                 }
                 o = &mut send => {
-                    self.metrics.add_sent_size(last_publish_payload_size);
                     // Send failure implies eventloop crash. Switch state to
                     // indefinitely write to disk to not loose data
+                    // NOTE: data of the failed publish isn't acked and is sent again after restart
                     let client = match o {
-                        Ok(c) => c,
-                        Err(MqttError::Send(Request::Publish(publish))) => break Ok(Status::EventLoopCrash(publish, last_publish_stream.clone())),
+                        Ok((c, throttled)) => {
+                            if !throttled.is_zero() {
+                                self.metrics.add_throttled(throttled);
+                            }
+                            self.add_sent_size(last_publish_payload_size);
+                            c
+                        }
+                        Err(MqttError::Send(Request::Publish(publish))) => {
+                            self.storage_handler.unsent = Some((last_publish_stream.clone(), last_publish_len));
+                            break Ok(Status::EventLoopCrash(publish, last_publish_stream_name.clone(), last_publish_stream.clone()))
+                        }
                         Err(e) => unreachable!("Unexpected error: {e}"),
                     };
+                    let seq = self.acks.sent(last_publish_qos);
+                    if last_publish_qos != QoS::AtMostOnce {
+                        backlog_inflight.push_back(seq);
+                    }
+                    self.storage_handler.track(seq, last_publish_stream.clone(), last_publish_len);
+                    self.commit_acks();
 
-                    let Some((stream, storage)) = self.storage_handler.next(&mut self.metrics) else {
+                    let Some((stream, storage)) = self.storage_handler.next(&mut self.metrics, &self.budget) else {
                         return Ok(Status::Normal);
                     };
 
-                    let publish = match Packet::read(storage.reader(), max_packet_size) {
-                        Ok(Packet::Publish(publish)) => publish,
-                        Ok(packet) => unreachable!("Unexpected packet: {:?}", packet),
+                    let unread = storage.reader().len();
+                    let publish = match read_publish(storage, max_packet_size, &mut self.metrics) {
+                        Ok(Some(publish)) => publish,
+                        // Only oversized records were left in the reader, continue with the next
+                        Ok(None) => {
+                            let (stream, len) = (stream.clone(), unread - storage.reader().len());
+                            self.track_skipped(stream, len);
+                            break Ok(Status::EventLoopReady)
+                        }
                         Err(e) => {
                             error!("Failed to read from storage. Forcing into Normal mode. Error = {e}");
                             break Ok(Status::Normal)
@@ -514,13 +896,20 @@ impl<C: MqttClient> Serializer<C> {
 
                     self.metrics.add_batch();
 
-                    let payload = publish.payload;
-                    last_publish_payload_size = payload.len();
+                    last_publish_payload_size = publish.payload.len();
+                    last_publish_len = unread - storage.reader().len();
                     last_publish_stream = stream.clone();
-                    send.set(send_publish(client, publish.topic, payload));
+                    last_publish_stream_name = Arc::new(storage.name().to_owned());
+                    last_publish_qos = publish.qos;
+                    let acks = self.acks.clone();
+                    let seq = backlog_ack_needed(&mut backlog_inflight, acks.acked(), backlog_limit);
+                    let limiter = self.rate_limiter.clone();
+                    send.set(send_bounded(client, publish, acks, seq, limiter, last_publish_stream_name.clone()));
                 }
                 // On a regular interval, forwards metrics information to network
                 _ = interval.tick() => {
+                    self.storage_handler.save_cursors();
+                    self.refresh_budget();
                     let _ = check_and_flush_metrics(&mut self.pending_metrics, &mut self.metrics, &self.metrics_tx, &self.storage_handler);
                 }
                 // Transition into crash mode when uplink is shutting down
@@ -545,30 +934,66 @@ impl<C: MqttClient> Serializer<C> {
         self.metrics.set_mode("normal");
         // Reactlabs setup processes logs generated by uplink
         info!("Switching to normal mode!!");
+        let max_packet_size = self.config.mqtt.max_packet_size;
 
         loop {
             select! {
                 data = self.collector_rx.recv_async() => {
                     let data = data?;
                     let stream = data.stream_config();
-                    let publish = construct_publish(data, &mut self.stream_metrics)?;
-                    let payload_size = publish.payload.len();
-                    debug!("publishing on {} with size = {payload_size}", publish.topic);
-                    match self.client.try_publish(&stream.topic, QoS::AtLeastOnce, false, publish.payload) {
-                        Ok(_) => {
+                    let stream_name = data.stream_name();
+                    let mut publishes = construct_publish(data, &mut self.stream_metrics, &self.dictionaries, max_packet_size)?.into_iter();
+                    // Data of persist-only streams is only written to storage
+                    if is_persist_only(&stream_name, &stream, &self.budget) {
+                        for publish in publishes {
+                            self.persist(publish, &stream_name, &stream);
                             self.metrics.add_batch();
-                            self.metrics.add_sent_size(payload_size);
-                            continue;
                         }
-                        Err(MqttError::TrySend(Request::Publish(publish))) => return Ok(Status::SlowEventloop(publish, stream)),
-                        Err(e) => unreachable!("Unexpected error: {e}"),
+                        continue;
                     }
 
+                    while let Some(publish) = publishes.next() {
+                        let payload_size = publish.payload.len();
+                        // Data in excess of rate limits is written to storage, to be sent from catchup mode
+                        if !self.rate_limiter.try_take(&stream_name, payload_size) {
+                            debug!("Rate limited on stream: {stream_name}, switching to catchup");
+                            self.metrics.add_throttled(Duration::ZERO);
+                            for publish in std::iter::once(publish).chain(publishes) {
+                                self.persist(publish, &stream_name, &stream);
+                                self.metrics.add_batch();
+                            }
+                            return Ok(Status::EventLoopReady);
+                        }
+                        debug!("publishing on {} with size = {payload_size}", publish.topic);
+                        let qos = to_qos(stream.qos);
+                        match self.client.try_publish(&stream.topic, qos, stream.retain, publish.payload) {
+                            Ok(_) => {
+                                self.acks.sent(qos);
+                                self.metrics.add_batch();
+                                self.add_sent_size(payload_size);
+                            }
+                            Err(MqttError::TrySend(Request::Publish(publish))) => {
+                                // Rest of a split batch is sent from storage, after the failed publish
+                                for publish in publishes {
+                                    self.persist(publish, &stream_name, &stream);
+                                    self.metrics.add_batch();
+                                }
+                                return Ok(Status::SlowEventloop(publish, stream_name, stream))
+                            }
+                            Err(e) => unreachable!("Unexpected error: {e}"),
+                        }
+                    }
                 }
                 // On a regular interval, forwards metrics information to network
                 _ = interval.tick() => {
                     // Check in storage stats every tick. TODO: Make storage object always
                     // available. It can be inmemory storage
+                    self.commit_acks();
+                    self.storage_handler.save_cursors();
+                    // Send data held back in storage, once the budget is replenished or an upload window opens
+                    if self.refresh_budget() || self.storage_handler.has_pending_upload(&self.budget) {
+                        return Ok(Status::EventLoopReady);
+                    }
 
                     if let Err(e) = check_and_flush_metrics(&mut self.pending_metrics, &mut self.metrics, &self.metrics_tx, &self.storage_handler) {
                         debug!("Failed to flush serializer metrics (normal). Error = {e}");
@@ -589,9 +1014,13 @@ impl<C: MqttClient> Serializer<C> {
         loop {
             let next_status = match status {
                 Status::Normal => self.normal().await?,
-                Status::SlowEventloop(publish, stream) => self.slow(publish, stream).await?,
+                Status::SlowEventloop(publish, name, stream) => {
+                    self.slow(publish, name, stream).await?
+                }
                 Status::EventLoopReady => self.catchup().await?,
-                Status::EventLoopCrash(publish, stream) => self.crash(publish, stream).await?,
+                Status::EventLoopCrash(publish, name, stream) => {
+                    self.crash(publish, name, stream).await?
+                }
                 Status::Shutdown => break,
             };
 
@@ -606,16 +1035,58 @@ impl<C: MqttClient> Serializer<C> {
     }
 }
 
-async fn send_publish<C: MqttClient>(
-    client: C,
-    topic: String,
-    payload: Bytes,
-) -> Result<C, MqttError> {
+async fn send_publish<C: MqttClient>(client: C, publish: Publish) -> Result<C, MqttError> {
+    let Publish { topic, qos, retain, payload, .. } = publish;
     debug!("publishing on {topic} with size = {}", payload.len());
-    client.publish(topic, QoS::AtLeastOnce, false, payload).await?;
+    client.publish(topic, qos, retain, payload).await?;
     Ok(client)
 }
 
+// Waits for publishes upto sequence number `seq` to be acknowledged and out rate limits of the stream
+// before sending, returns the client along with the time held back by rate limits
+async fn send_bounded<C: MqttClient>(
+    client: C,
+    publish: Publish,
+    acks: PublishAcks,
+    seq: usize,
+    rate_limiter: RateLimiter,
+    stream_name: Arc<String>,
+) -> Result<(C, Duration), MqttError> {
+    acks.wait_for_acked(seq).await;
+    let throttled = rate_limiter.acquire(&stream_name, publish.payload.len()).await;
+    let client = send_publish(client, publish).await?;
+    Ok((client, throttled))
+}
+
+// Sequence number that has to be acknowledged for fewer than `limit` publishes of the backlog to be
+// inflight. Publishes sent from elsewhere, e.g. live data, aren't counted against the limit.
+fn backlog_ack_needed(inflight: &mut VecDeque<usize>, acked: usize, limit: usize) -> usize {
+    while inflight.front().is_some_and(|seq| *seq <= acked) {
+        inflight.pop_front();
+    }
+
+    match inflight.len().checked_sub(limit) {
+        Some(i) => inflight[i],
+        None => 0,
+    }
+}
+
+// Data of a stream is only written to storage outside its upload windows, or while held back by an exhausted budget
+fn is_persist_only(stream_name: &str, stream: &StreamConfig, budget: &Budget) -> bool {
+    let time_of_day = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() % 86400;
+
+    !stream.in_upload_window(time_of_day as u32) || !budget.allows(stream_name, stream.priority)
+}
+
+// Converts QoS level of a stream's config into [QoS]
+fn to_qos(level: u8) -> QoS {
+    match level {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        _ => QoS::ExactlyOnce,
+    }
+}
+
 fn lz4_compress(payload: &mut Vec<u8>) -> Result<(), Error> {
     let mut compressor = FrameEncoder::new(vec![]);
     compressor.write_all(payload)?;
@@ -624,43 +1095,141 @@ fn lz4_compress(payload: &mut Vec<u8>) -> Result<(), Error> {
     Ok(())
 }
 
+fn zstd_compress(
+    payload: &mut Vec<u8>,
+    level: i32,
+    dictionary: Option<&EncoderDictionary>,
+) -> Result<(), Error> {
+    let mut compressor = match dictionary {
+        Some(dictionary) => zstd::Encoder::with_prepared_dictionary(vec![], dictionary)?,
+        None => zstd::Encoder::new(vec![], level)?,
+    };
+    // Id of the dictionary in frame header lets the backend pick the dictionary to decompress with
+    compressor.include_dictid(true)?;
+    compressor.write_all(payload)?;
+    *payload = compressor.finish()?;
+
+    Ok(())
+}
+
+// Loads zstd dictionaries configured for streams from disk, prepared for the configured compression level
+fn load_dictionaries(
+    config: &Config,
+) -> Result<HashMap<String, EncoderDictionary<'static>>, Error> {
+    let mut dictionaries = HashMap::new();
+    let mut streams = config.streams.clone();
+    streams.insert("action_status".into(), config.action_status.clone());
+    for (stream_name, stream_config) in streams {
+        if let Compression::Zstd { level, dictionary: Some(path) } = stream_config.compression {
+            let dictionary =
+                std::fs::read(&path).map_err(|e| Error::Dictionary(path.clone(), e))?;
+            debug!("Loaded zstd dictionary for stream: {stream_name:?}; path: {}", path.display());
+            dictionaries.insert(stream_name, EncoderDictionary::copy(&dictionary, level));
+        }
+    }
+
+    Ok(dictionaries)
+}
+
 // Constructs a [Publish] packet given a [Package] element. Updates stream metrics as necessary.
+// Serializes and compresses data into publishes, batches larger than `max_packet_size` are split
+// in halves until they fit. Data points that don't fit in a publish on their own are dropped.
 fn construct_publish(
     data: Box<dyn Package>,
     stream_metrics: &mut HashMap<String, StreamMetrics>,
-) -> Result<Publish, Error> {
+    dictionaries: &HashMap<String, EncoderDictionary>,
+    max_packet_size: usize,
+) -> Result<Vec<Publish>, Error> {
     let stream_name = data.stream_name().as_ref().to_owned();
     let stream_config = data.stream_config();
     let point_count = data.len();
     let batch_latency = data.latency();
     trace!("Data received on stream: {stream_name}; message count = {point_count}; batching latency = {batch_latency}");
 
-    let topic = stream_config.topic.clone();
-
     let metrics = stream_metrics
         .entry(stream_name.clone())
         .or_insert_with(|| StreamMetrics::new(&stream_name));
 
-    let serialization_start = Instant::now();
-    let mut payload = data.serialize()?;
-    let serialization_time = serialization_start.elapsed();
-    metrics.add_serialization_time(serialization_time);
+    let mut publishes = vec![];
+    let mut pending = vec![data];
+    while let Some(data) = pending.pop() {
+        let serialization_start = Instant::now();
+        let mut payload = data.serialize()?;
+        let serialization_time = serialization_start.elapsed();
+        metrics.add_serialization_time(serialization_time);
 
-    let data_size = payload.len();
-    let mut compressed_data_size = None;
+        let data_size = payload.len();
+        let mut compressed_data_size = None;
 
-    if let Compression::Lz4 = stream_config.compression {
         let compression_start = Instant::now();
-        lz4_compress(&mut payload)?;
-        let compression_time = compression_start.elapsed();
-        metrics.add_compression_time(compression_time);
+        let compressed = match &stream_config.compression {
+            Compression::Disabled => false,
+            Compression::Lz4 => {
+                lz4_compress(&mut payload)?;
+                true
+            }
+            Compression::Zstd { level, .. } => {
+                zstd_compress(&mut payload, *level, dictionaries.get(&stream_name))?;
+                true
+            }
+        };
+
+        if compressed {
+            let compression_time = compression_start.elapsed();
+            metrics.add_compression_time(compression_time);
 
-        compressed_data_size = Some(payload.len());
+            compressed_data_size = Some(payload.len());
+        }
+
+        let mut publish = Publish::new(&stream_config.topic, to_qos(stream_config.qos), payload);
+        publish.retain = stream_config.retain;
+
+        if publish.size() > max_packet_size {
+            if data.len() > 1 {
+                let (first, second) = data.split();
+                pending.push(second);
+                pending.push(first);
+                metrics.increment_splits();
+            } else {
+                error!(
+                    "Dropping data point larger than max_packet_size on stream: {stream_name}; size = {}",
+                    publish.size()
+                );
+                metrics.increment_oversized_drops();
+            }
+            continue;
+        }
+
+        metrics.add_serialized_sizes(data_size, compressed_data_size);
+        publishes.push(publish);
     }
 
-    metrics.add_serialized_sizes(data_size, compressed_data_size);
+    Ok(publishes)
+}
 
-    Ok(Publish::new(topic, QoS::AtLeastOnce, payload))
+// Reads the next publish from storage, records larger than `max_packet_size` are dropped so that
+// they don't block the rest of the backlog. Returns `None` if no publishes are left in the reader.
+fn read_publish(
+    storage: &mut Storage,
+    max_packet_size: usize,
+    metrics: &mut Metrics,
+) -> Result<Option<Publish>, rumqttc::mqttbytes::Error> {
+    while !storage.reader().is_empty() {
+        match Packet::read(storage.reader(), usize::MAX)? {
+            Packet::Publish(publish) if publish.size() > max_packet_size => {
+                error!(
+                    "Dropping record larger than max_packet_size from storage: {}; size = {}",
+                    storage.name(),
+                    publish.size()
+                );
+                metrics.add_oversized_record(publish.size());
+            }
+            Packet::Publish(publish) => return Ok(Some(publish)),
+            packet => unreachable!("Unexpected packet: {:?}", packet),
+        }
+    }
+
+    Ok(None)
 }
 
 // Writes the provided publish packet to [Storage], after setting its pkid to 1.
@@ -850,6 +1419,7 @@ pub(crate) struct SerializerShutdown;
 #[derive(Debug, Clone)]
 pub struct CtrlTx {
     pub(crate) inner: Sender<SerializerShutdown>,
+    rate_limiter: RateLimiter,
 }
 
 impl CtrlTx {
@@ -857,6 +1427,12 @@ impl CtrlTx {
     pub async fn trigger_shutdown(&self) {
         self.inner.send_async(SerializerShutdown).await.unwrap()
     }
+
+    /// Updates rate limit of a stream, or the device wide rate limit if stream isn't mentioned,
+    /// `None` removes the limit
+    pub fn set_rate_limit(&self, stream: Option<&str>, limit: Option<RateLimit>) {
+        self.rate_limiter.set(stream, limit)
+    }
 }
 
 // TODO(RT): Test cases
@@ -868,7 +1444,7 @@ pub mod tests {
     use tokio::{spawn, time::sleep};
 
     use crate::{
-        config::MqttConfig,
+        config::{Encoding, MqttConfig, Persistence, UploadWindow},
         mock::{MockClient, MockCollector},
     };
 
@@ -906,7 +1482,28 @@ pub mod tests {
         let (metrics_tx, _metrics_rx) = bounded(1);
         let client = MockClient { net_tx };
 
-        (Serializer::new(config, data_rx, client, metrics_tx).unwrap(), data_tx, net_rx)
+        (
+            Serializer::new(config, data_rx, client, metrics_tx, PublishAcks::default()).unwrap(),
+            data_tx,
+            net_rx,
+        )
+    }
+
+    /// Batches data points with `sequences` into a package of the stream, as the bridge would
+    async fn batch(
+        stream_name: &str,
+        stream_config: StreamConfig,
+        sequences: std::ops::Range<u32>,
+    ) -> Box<dyn Package> {
+        let (data_tx, data_rx) = bounded(1);
+        let mut collector = MockCollector::new(stream_name, stream_config, data_tx);
+        spawn(async move {
+            for i in sequences {
+                collector.send(i).await.unwrap();
+            }
+        });
+
+        data_rx.recv_async().await.unwrap()
     }
 
     #[tokio::test]
@@ -935,7 +1532,7 @@ pub mod tests {
         });
 
         match serializer.normal().await.unwrap() {
-            Status::SlowEventloop(Publish { qos: QoS::AtLeastOnce, topic, payload, .. }, _) => {
+            Status::SlowEventloop(Publish { qos: QoS::AtLeastOnce, topic, payload, .. }, ..) => {
                 assert_eq!(topic, "hello/world");
                 let recvd: Value = serde_json::from_slice(&payload).unwrap();
                 let obj = &recvd.as_array().unwrap()[0];
@@ -1001,7 +1598,10 @@ pub mod tests {
             QoS::AtLeastOnce,
             "[{{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}}]".as_bytes(),
         );
-        let status = serializer.slow(publish, Arc::new(Default::default())).await.unwrap();
+        let status = serializer
+            .slow(publish, Arc::new("hello".to_owned()), Arc::new(Default::default()))
+            .await
+            .unwrap();
 
         assert_eq!(status, Status::EventLoopReady);
     }
@@ -1034,12 +1634,13 @@ pub mod tests {
         match serializer
             .slow(
                 publish,
+                Arc::new("hello".to_owned()),
                 Arc::new(StreamConfig { topic: "hello/world".to_string(), ..Default::default() }),
             )
             .await
             .unwrap()
         {
-            Status::EventLoopCrash(Publish { qos: QoS::AtLeastOnce, topic, payload, .. }, _) => {
+            Status::EventLoopCrash(Publish { qos: QoS::AtLeastOnce, topic, payload, .. }, ..) => {
                 assert_eq!(topic, "hello/world");
                 let recvd = std::str::from_utf8(&payload).unwrap();
                 assert_eq!(recvd, "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]");
@@ -1152,7 +1753,7 @@ pub mod tests {
         write_to_storage(publish.clone(), &mut storage).unwrap();
 
         match serializer.catchup().await.unwrap() {
-            Status::EventLoopCrash(Publish { topic, payload, .. }, _) => {
+            Status::EventLoopCrash(Publish { topic, payload, .. }, ..) => {
                 assert_eq!(topic, "hello/world");
                 let recvd = std::str::from_utf8(&payload).unwrap();
                 assert_eq!(recvd, "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]");
@@ -1296,4 +1897,911 @@ pub mod tests {
         assert_eq!(topic, "topic/default");
         assert_eq!(payload, "2");
     }
+
+    #[tokio::test(start_paused = true)]
+    // Force runs serializer in crash mode, with eventloop recreated later
+    async fn crash_to_catchup() {
+        let config = Arc::new(default_config());
+        let (mut serializer, _data_tx, net_rx) = defaults(config);
+
+        // Eventloop has crashed, it is recreated in 5s
+        drop(net_rx);
+        let client_tx = serializer.client_tx();
+        let (net_tx, net_rx) = bounded(1);
+        spawn(async move {
+            sleep(Duration::from_secs(5)).await;
+            client_tx.send(MockClient { net_tx }).unwrap();
+        });
+
+        let publish = Publish::new(
+            "hello/world",
+            QoS::AtLeastOnce,
+            "[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]".as_bytes(),
+        );
+        let stream = Arc::new(StreamConfig::default());
+        let status = serializer.crash(publish, Arc::new("hello".to_owned()), stream).await.unwrap();
+
+        match serializer.pending_metrics.front() {
+            Some(SerializerMetrics::Main(metrics)) => {
+                assert_eq!(metrics.mode, "crash");
+                assert!(metrics.crash_duration >= Duration::from_secs(5));
+            }
+            _ => panic!("Crash metrics weren't saved"),
+        }
+
+        // Failed publish is retried with client of the new eventloop
+        let Status::SlowEventloop(publish, stream_name, stream) = status else {
+            panic!("Unexpected status: {:?}", status)
+        };
+        let status = serializer.slow(publish, stream_name, stream).await.unwrap();
+        assert_eq!(status, Status::EventLoopReady);
+        match net_rx.try_recv().unwrap() {
+            Request::Publish(Publish { topic, .. }) => assert_eq!(topic, "hello/world"),
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Force runs serializer in crash mode, with eventloop resuming without being recreated
+    async fn crash_to_catchup_without_recreated_eventloop() {
+        let config = Arc::new(default_config());
+        let (mut serializer, _data_tx, net_rx) = defaults(config);
+
+        // Eventloop has stalled with its request channel full, it resumes in 7s
+        let stalled = Publish::new("hello/stalled", QoS::AtLeastOnce, "stalled");
+        serializer.client.net_tx.try_send(Request::Publish(stalled)).unwrap();
+
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, "failed");
+        let stream = Arc::new(StreamConfig::default());
+        let crash = spawn(async move {
+            let status =
+                serializer.crash(publish, Arc::new("hello".to_owned()), stream).await.unwrap();
+            (serializer, status)
+        });
+
+        // Probes fail while the eventloop is stalled
+        sleep(Duration::from_secs(7)).await;
+        match net_rx.try_recv().unwrap() {
+            Request::Publish(Publish { topic, .. }) => assert_eq!(topic, "hello/stalled"),
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+        assert!(net_rx.is_empty());
+
+        // Failed publish is accepted on the next probe, serializer catches up from here-on
+        let (serializer, status) = crash.await.unwrap();
+        assert_eq!(status, Status::EventLoopReady);
+        match net_rx.try_recv().unwrap() {
+            Request::Publish(Publish { payload, .. }) => assert_eq!(payload, "failed"),
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+        match serializer.pending_metrics.front() {
+            Some(SerializerMetrics::Main(metrics)) => {
+                assert_eq!(metrics.mode, "crash");
+                assert_eq!(metrics.sent_size, 6);
+                assert!(metrics.crash_duration >= Duration::from_secs(10));
+            }
+            _ => panic!("Crash metrics weren't saved"),
+        }
+    }
+    #[tokio::test]
+    // Ensures that payloads are compressed with the stream's zstd dictionary, whose id is in the frame header
+    async fn zstd_compression_with_dictionary() {
+        let dir = tempdir::TempDir::new("uplink-zstd").unwrap();
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| {
+                format!("[{{\"sequence\":{i},\"timestamp\":{},\"msg\":\"Hello, World!\"}}]", i * 7)
+                    .into_bytes()
+            })
+            .collect();
+        let dictionary = zstd::dict::from_samples(&samples, 1024).unwrap();
+        let path = dir.path().join("hello.dict");
+        std::fs::write(&path, &dictionary).unwrap();
+
+        let mut config = default_config();
+        let stream_config = StreamConfig {
+            topic: "hello/world/zstd".to_string(),
+            batch_size: 1,
+            compression: Compression::Zstd { level: 3, dictionary: Some(path) },
+            ..Default::default()
+        };
+        config.streams.insert("hello".to_owned(), stream_config.clone());
+        let (serializer, _, _) = defaults(Arc::new(config));
+
+        let data = batch("hello", stream_config, 1..2).await;
+        let mut stream_metrics = HashMap::new();
+        let publish =
+            construct_publish(data, &mut stream_metrics, &serializer.dictionaries, usize::MAX)
+                .unwrap()
+                .remove(0);
+
+        let dict_id = zstd::zstd_safe::get_dict_id_from_dict(&dictionary);
+        assert!(dict_id.is_some());
+        assert_eq!(zstd::zstd_safe::get_dict_id_from_frame(&publish.payload), dict_id);
+
+        let mut decompressor = zstd::bulk::Decompressor::with_dictionary(&dictionary).unwrap();
+        let payload = decompressor.decompress(&publish.payload, 1024).unwrap();
+        assert_eq!(payload, b"[{\"sequence\":1,\"timestamp\":0,\"msg\":\"Hello, World!\"}]");
+
+        let metrics = stream_metrics.get_mut("hello").unwrap();
+        metrics.prepare_snapshot();
+        assert!(metrics.compression_ratio > 0.0);
+
+        // Ratio isn't carried over into snapshots of windows without compressed data
+        metrics.prepare_next();
+        metrics.prepare_snapshot();
+        assert_eq!(metrics.compression_ratio, 0.0);
+    }
+
+    #[tokio::test]
+    // Ensures that batches are serialized in the stream's encoding, published on a matching topic
+    async fn binary_encodings() {
+        let (data_tx, _data_rx) = bounded(1);
+        let stream: crate::Stream<crate::Payload> =
+            crate::Stream::dynamic("hello", "demo", "123", Encoding::Cbor, data_tx);
+        assert_eq!(stream.config.topic, "/tenants/demo/devices/123/events/hello/cborarray");
+
+        let mut stream_metrics = HashMap::new();
+        let dictionaries = HashMap::new();
+        let stream_config = StreamConfig { batch_size: 1, ..(*stream.config).clone() };
+        let data = batch("hello", stream_config, 1..2).await;
+        let publish = construct_publish(data, &mut stream_metrics, &dictionaries, usize::MAX)
+            .unwrap()
+            .remove(0);
+        let recvd: Vec<Value> = ciborium::from_reader(&publish.payload[..]).unwrap();
+        assert_eq!(recvd[0].get("sequence"), Some(&Value::from(1)));
+        assert_eq!(recvd[0].get("msg"), Some(&Value::from("Hello, World!")));
+
+        let stream_config = StreamConfig {
+            topic: "hello/world/msgpackarray".to_string(),
+            batch_size: 1,
+            encoding: Encoding::MessagePack,
+            ..Default::default()
+        };
+        let data = batch("hello", stream_config, 2..3).await;
+        let publish = construct_publish(data, &mut stream_metrics, &dictionaries, usize::MAX)
+            .unwrap()
+            .remove(0);
+        let recvd: Vec<Value> = rmp_serde::from_slice(&publish.payload).unwrap();
+        assert_eq!(recvd[0].get("sequence"), Some(&Value::from(2)));
+        assert_eq!(recvd[0].get("msg"), Some(&Value::from("Hello, World!")));
+    }
+
+    #[tokio::test]
+    // Ensures that batches of columnar streams have field names once, with delta encoded sequences
+    async fn columnar_batches() {
+        let stream_config = StreamConfig {
+            topic: "hello/world".to_string(),
+            batch_size: 3,
+            columnar: true,
+            ..Default::default()
+        };
+        let data = batch("hello", stream_config, 5..8).await;
+        let publish = construct_publish(data, &mut HashMap::new(), &HashMap::new(), usize::MAX)
+            .unwrap()
+            .remove(0);
+        let recvd: Value = serde_json::from_slice(&publish.payload).unwrap();
+        let expected = serde_json::json!({
+            "sequence": [5, 1, 1],
+            "timestamp": [0, 0, 0],
+            "fields": { "msg": ["Hello, World!", "Hello, World!", "Hello, World!"] }
+        });
+        assert_eq!(recvd, expected);
+    }
+
+    #[tokio::test]
+    // Ensures that qos and retain of a stream are retained when its data is replayed from storage
+    async fn qos_and_retain_from_storage() {
+        let mut config = default_config();
+        let stream_config = StreamConfig {
+            topic: "hello/world".to_string(),
+            batch_size: 1,
+            qos: 0,
+            retain: true,
+            ..Default::default()
+        };
+        config.streams.insert("hello".to_owned(), stream_config.clone());
+        let (mut serializer, _data_tx, net_rx) = defaults(Arc::new(config));
+
+        let data = batch("hello", stream_config, 1..2).await;
+        let publish = construct_publish(data, &mut HashMap::new(), &HashMap::new(), usize::MAX)
+            .unwrap()
+            .remove(0);
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "hello").unwrap();
+        write_to_storage(publish, storage).unwrap();
+
+        spawn(async move { serializer.catchup().await.unwrap() });
+
+        match net_rx.recv_async().await.unwrap() {
+            Request::Publish(Publish { qos, retain, topic, .. }) => {
+                assert_eq!(topic, "hello/world");
+                assert_eq!(qos, QoS::AtMostOnce);
+                assert!(retain);
+            }
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    // Ensures that data in excess of a rate limit, set at runtime, is written to storage
+    async fn rate_limited_data_to_storage() {
+        let mut config = default_config();
+        let stream_config =
+            StreamConfig { topic: "hello/world".to_string(), batch_size: 1, ..Default::default() };
+        config.streams.insert("hello".to_owned(), stream_config.clone());
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+        let limit = RateLimit { bytes_per_second: 10, burst: None };
+        serializer.ctrl_tx().set_rate_limit(Some("hello"), Some(limit));
+
+        let mut collector = MockCollector::new("hello", stream_config.clone(), data_tx);
+        spawn(async move {
+            for i in 1..3 {
+                collector.send(i).await.unwrap();
+            }
+        });
+
+        // First batch is sent within the burst, second is held back
+        assert_eq!(serializer.normal().await.unwrap(), Status::EventLoopReady);
+        assert_eq!(serializer.metrics.throttled, 1);
+        match net_rx.try_recv().unwrap() {
+            Request::Publish(Publish { topic, .. }) => assert_eq!(topic, "hello/world"),
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+        assert!(net_rx.is_empty());
+
+        let storage = serializer.storage_handler.select("hello", &Arc::new(stream_config));
+        let publish = read_from_storage(storage, 1024);
+        let recvd: Value = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(recvd.as_array().unwrap()[0].get("sequence"), Some(&Value::from(2)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Ensures that only exempted streams are sent once the data budget is exhausted,
+    // with usage carried over across restarts
+    async fn exhausted_budget_holds_back_low_priority() {
+        let dir = tempdir::TempDir::new("uplink-budget").unwrap();
+        let mut config = default_config();
+        config.persistence_path = dir.path().to_owned();
+        config.budget =
+            Some(crate::config::DataBudget { daily: Some(10), monthly: None, priority: 100 });
+        let low =
+            StreamConfig { topic: "topic/low".to_string(), batch_size: 1, ..Default::default() };
+        let high = StreamConfig {
+            topic: "topic/high".to_string(),
+            batch_size: 1,
+            priority: 200,
+            ..Default::default()
+        };
+        config.streams.insert("low".to_owned(), low.clone());
+        let config = Arc::new(config);
+        let (mut serializer, data_tx, net_rx) = defaults(config.clone());
+
+        let mut low_collector = MockCollector::new("low", low.clone(), data_tx.clone());
+        let mut high_collector = MockCollector::new("high", high, data_tx.clone());
+        spawn(async move {
+            low_collector.send(1).await.unwrap();
+            low_collector.send(2).await.unwrap();
+            high_collector.send(3).await.unwrap();
+        });
+        let (topic_tx, topic_rx) = flume::unbounded();
+        spawn(async move {
+            while let Ok(Request::Publish(publish)) = net_rx.recv_async().await {
+                topic_tx.send(publish.topic).unwrap();
+            }
+        });
+
+        // Normal mode runs until timed out, while data_tx is held
+        let _ = tokio::time::timeout(Duration::from_secs(1), serializer.normal()).await;
+        let topics: Vec<String> = topic_rx.drain().collect();
+        assert_eq!(topics, vec!["topic/low", "topic/high"]);
+
+        let storage = serializer.storage_handler.select("low", &Arc::new(low));
+        let publish = read_from_storage(storage, 1024);
+        let recvd: Value = serde_json::from_slice(&publish.payload).unwrap();
+        assert_eq!(recvd.as_array().unwrap()[0].get("sequence"), Some(&Value::from(2)));
+
+        serializer.refresh_budget();
+        assert!(serializer.metrics.budget_exhausted);
+        let restarted = Budget::new(&config);
+        assert!(restarted.exhausted());
+        assert_eq!(restarted.usage(), serializer.budget.usage());
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Ensures that data of streams outside their upload window is only written to storage
+    async fn upload_windows() {
+        let mut config = default_config();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        // Window from `start` to `end` seconds relative to now
+        let window = |start: i64, end: i64| UploadWindow {
+            start: (now + start).rem_euclid(86400) as u32,
+            end: (now + end).rem_euclid(86400) as u32,
+        };
+        let closed = StreamConfig {
+            topic: "topic/closed".to_string(),
+            batch_size: 1,
+            upload_windows: vec![window(3600, 7200)],
+            ..Default::default()
+        };
+        let open = StreamConfig {
+            topic: "topic/open".to_string(),
+            batch_size: 1,
+            upload_windows: vec![window(-3600, 3600)],
+            ..Default::default()
+        };
+        config.streams.insert("closed".to_owned(), closed.clone());
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        let mut closed_collector = MockCollector::new("closed", closed.clone(), data_tx.clone());
+        let mut open_collector = MockCollector::new("open", open, data_tx.clone());
+        spawn(async move {
+            closed_collector.send(1).await.unwrap();
+            open_collector.send(2).await.unwrap();
+        });
+        let (topic_tx, topic_rx) = flume::unbounded();
+        spawn(async move {
+            while let Ok(Request::Publish(publish)) = net_rx.recv_async().await {
+                topic_tx.send(publish.topic).unwrap();
+            }
+        });
+
+        // Normal mode runs until timed out, while data_tx is held
+        let _ = tokio::time::timeout(Duration::from_secs(1), serializer.normal()).await;
+        let topics: Vec<String> = topic_rx.drain().collect();
+        assert_eq!(topics, vec!["topic/open"]);
+
+        // Backlog of the stream isn't drained outside its window
+        assert!(!serializer.storage_handler.has_pending_upload(&serializer.budget));
+        assert_eq!(serializer.catchup().await.unwrap(), Status::Normal);
+        let storage = serializer.storage_handler.select("closed", &Arc::new(closed));
+        let publish = read_from_storage(storage, 1024);
+        assert_eq!(publish.topic, "topic/closed");
+    }
+
+    #[tokio::test]
+    // Ensures that batches larger than max_packet_size are split and oversized records in storage are skipped
+    async fn oversized_batches_and_records() {
+        let stream_config =
+            StreamConfig { topic: "hello/world".to_string(), batch_size: 4, ..Default::default() };
+        let data = batch("hello", stream_config.clone(), 1..5).await;
+        let mut stream_metrics = HashMap::new();
+        let publishes = construct_publish(data, &mut stream_metrics, &HashMap::new(), 150).unwrap();
+        let sequences: Vec<Vec<u64>> = publishes
+            .iter()
+            .map(|publish| {
+                assert!(publish.size() <= 150);
+                let recvd: Vec<Value> = serde_json::from_slice(&publish.payload).unwrap();
+                recvd.iter().map(|v| v.get("sequence").unwrap().as_u64().unwrap()).collect()
+            })
+            .collect();
+        assert_eq!(sequences, vec![vec![1, 2], vec![3, 4]]);
+        assert_eq!(stream_metrics.get("hello").unwrap().splits, 1);
+
+        let mut config = default_config();
+        config.mqtt.max_packet_size = 1024;
+        config.streams.insert("hello".to_owned(), stream_config);
+        let (mut serializer, _data_tx, net_rx) = defaults(Arc::new(config));
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "hello").unwrap();
+        let oversized = Publish::new("hello/world", QoS::AtLeastOnce, vec![0; 2048]);
+        write_to_storage(oversized, storage).unwrap();
+        write_to_storage(publishes[0].clone(), storage).unwrap();
+
+        spawn(async move { serializer.catchup().await.unwrap() });
+
+        match net_rx.recv_async().await.unwrap() {
+            Request::Publish(publish) => assert_eq!(publish.payload, publishes[0].payload),
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    // Ensures that live data of high priority streams isn't held back behind the backlog
+    async fn live_data_bypasses_backlog() {
+        let mut config = default_config();
+        config.mqtt.max_inflight = 4;
+        config.catchup.live_priority = Some(100);
+        let backlog =
+            StreamConfig { topic: "topic/backlog".to_string(), priority: 1, ..Default::default() };
+        let live = StreamConfig {
+            topic: "topic/live".to_string(),
+            batch_size: 1,
+            priority: u8::MAX,
+            ..Default::default()
+        };
+        config.streams.insert("backlog".to_owned(), backlog.clone());
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        let storage = serializer.storage_handler.map.get_mut(&backlog).unwrap();
+        for i in 1..6 {
+            let publish = Publish::new("topic/backlog", QoS::AtLeastOnce, i.to_string());
+            write_to_storage(publish, storage).unwrap();
+        }
+
+        // Nothing is acked, so only 50% of max_inflight are used by the backlog
+        spawn(async move { serializer.catchup().await.unwrap() });
+
+        for i in 1..3 {
+            let Request::Publish(Publish { topic, payload, .. }) =
+                net_rx.recv_async().await.unwrap()
+            else {
+                unreachable!()
+            };
+            assert_eq!(topic, "topic/backlog");
+            assert_eq!(payload, i.to_string());
+        }
+
+        let mut collector = MockCollector::new("live", live, data_tx);
+        collector.send(1).await.unwrap();
+
+        let Request::Publish(Publish { topic, .. }) = net_rx.recv_async().await.unwrap() else {
+            unreachable!()
+        };
+        assert_eq!(topic, "topic/live");
+    }
+
+    #[test]
+    // Ensures that files of lower priority streams are deleted first when over disk quota
+    fn disk_quota_evicts_low_priority_first() {
+        let dir = tempdir::TempDir::new("uplink-quota").unwrap();
+        let mut config = default_config();
+        config.persistence_path = dir.path().to_owned();
+        config.persistence.max_disk_bytes = Some(1200);
+        let persistence =
+            Persistence { max_file_size: 100, max_file_count: 10, ..Default::default() };
+        config.streams.extend([
+            (
+                "low".to_owned(),
+                StreamConfig {
+                    topic: "topic/low".to_string(),
+                    priority: 1,
+                    persistence: persistence.clone(),
+                    ..Default::default()
+                },
+            ),
+            (
+                "high".to_owned(),
+                StreamConfig {
+                    topic: "topic/high".to_string(),
+                    priority: 2,
+                    persistence,
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let (mut serializer, _, _) = defaults(Arc::new(config));
+
+        // Write 5 files of 200+ bytes each to both streams
+        for storage in serializer.storage_handler.map.values_mut() {
+            for _ in 0..5 {
+                let publish = Publish::new(storage.name(), QoS::AtLeastOnce, vec![1; 200]);
+                write_to_storage(publish, storage).unwrap();
+            }
+        }
+
+        serializer
+            .storage_handler
+            .enforce_disk_quota(&mut serializer.metrics, &mut serializer.stream_metrics);
+
+        let mut disk_utilized = 0;
+        for storage in serializer.storage_handler.map.values() {
+            disk_utilized += storage.disk_utilized();
+            match storage.name() {
+                "high" => assert_eq!(storage.file_count(), 5),
+                "low" => assert_eq!(storage.file_count(), 0),
+                _ => {}
+            }
+        }
+        assert!(disk_utilized <= 1200);
+        for i in 0..5 {
+            assert!(!dir.path().join(format!("low/backup@{i}")).exists());
+            assert!(dir.path().join(format!("high/backup@{i}")).is_file());
+        }
+        assert_eq!(serializer.metrics.lost_segments, 5);
+        assert_eq!(serializer.stream_metrics.get("low").unwrap().lost_segments, 5);
+        assert!(!serializer.stream_metrics.contains_key("high"));
+    }
+
+    #[test]
+    // Ensures that persisted data older than max_age is deleted without being read
+    fn expired_files_are_skipped() {
+        let dir = tempdir::TempDir::new("uplink-expiry").unwrap();
+        let mut config = default_config();
+        config.persistence_path = dir.path().to_owned();
+        let persistence = Persistence {
+            max_file_size: 100,
+            max_file_count: 10,
+            max_age: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        config.streams.insert(
+            "imu".to_owned(),
+            StreamConfig { topic: "topic/imu".to_string(), persistence, ..Default::default() },
+        );
+        let max_packet_size = config.mqtt.max_packet_size;
+        let (mut serializer, _, _) = defaults(Arc::new(config));
+
+        // Write 2 files of 200+ bytes each
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "imu").unwrap();
+        for i in 0..2 {
+            let publish = Publish::new("topic/imu", QoS::AtLeastOnce, vec![i; 200]);
+            write_to_storage(publish, storage).unwrap();
+        }
+
+        // First file was written 2 minutes ago
+        let file =
+            std::fs::File::options().write(true).open(dir.path().join("imu/backup@0")).unwrap();
+        file.set_modified(std::time::SystemTime::now() - Duration::from_secs(120)).unwrap();
+        assert!(dir.path().join("imu/backup@1").is_file());
+
+        let (_, storage) =
+            serializer.storage_handler.next(&mut serializer.metrics, &serializer.budget).unwrap();
+        let publish = read_from_storage(storage, max_packet_size);
+        assert_eq!(publish.payload, vec![1; 200]);
+        assert!(!dir.path().join("imu/backup@0").exists());
+        assert_eq!(serializer.metrics.expired_segments, 1);
+        assert_eq!(serializer.metrics.lost_segments, 0);
+    }
+
+    #[tokio::test]
+    // Ensures that data of oversized records dropped from storage is committed along with acks
+    async fn skipped_records_are_acked() {
+        let dir = tempdir::TempDir::new("uplink-skipped").unwrap();
+        let mut config = default_config();
+        config.persistence_path = dir.path().to_owned();
+        config.persistence.delete_on_ack = true;
+        config.mqtt.max_packet_size = 1024;
+        let persistence =
+            Persistence { max_file_size: 100, max_file_count: 10, ..Default::default() };
+        config.streams.insert(
+            "hello".to_owned(),
+            StreamConfig { topic: "hello/world".to_string(), persistence, ..Default::default() },
+        );
+        let (mut serializer, _data_tx, net_rx) = defaults(Arc::new(config));
+
+        // A publish followed by an oversized record, in the same file
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "hello").unwrap();
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, vec![1; 10]);
+        write_to_storage(publish, storage).unwrap();
+        let oversized = Publish::new("hello/world", QoS::AtLeastOnce, vec![2; 2048]);
+        write_to_storage(oversized, storage).unwrap();
+        assert!(dir.path().join("hello/backup@0").is_file());
+
+        assert_eq!(serializer.catchup().await.unwrap(), Status::EventLoopReady);
+        match net_rx.try_recv().unwrap() {
+            Request::Publish(Publish { payload, .. }) => assert_eq!(payload, vec![1; 10]),
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+
+        // Oversized record is counted apart from data sent
+        let Some(SerializerMetrics::Main(metrics)) = serializer.pending_metrics.pop_back() else {
+            unreachable!("Metrics of catchup should be pending")
+        };
+        assert_eq!(metrics.sent_size, 10);
+        assert_eq!(metrics.oversized_records, 1);
+        assert!(metrics.oversized_size > 2048);
+
+        // File is deleted once the publish sent from it is acked
+        serializer.acks.update(0);
+        serializer.commit_acks();
+        assert!(!dir.path().join("hello/backup@0").exists());
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Ensures that live data sent during catchup isn't counted against inflight limit of the backlog
+    async fn backlog_limit_excludes_live_data() {
+        let mut config = default_config();
+        config.mqtt.max_inflight = 4;
+        config.catchup.live_priority = Some(100);
+        let backlog =
+            StreamConfig { topic: "topic/backlog".to_string(), priority: 1, ..Default::default() };
+        let live = StreamConfig {
+            topic: "topic/live".to_string(),
+            batch_size: 1,
+            priority: u8::MAX,
+            ..Default::default()
+        };
+        config.streams.insert("backlog".to_owned(), backlog.clone());
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+        let acks = serializer.acks.clone();
+
+        let storage = serializer.storage_handler.map.get_mut(&backlog).unwrap();
+        for i in 1..6 {
+            let publish = Publish::new("topic/backlog", QoS::AtLeastOnce, i.to_string());
+            write_to_storage(publish, storage).unwrap();
+        }
+        spawn(async move { serializer.catchup().await.unwrap() });
+
+        let recv_topic = || async {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv_async()).await {
+                Ok(Ok(Request::Publish(Publish { topic, payload, .. }))) => {
+                    Some(format!("{topic}:{}", std::str::from_utf8(&payload).unwrap()))
+                }
+                Ok(r) => unreachable!("Unexpected request: {:?}", r),
+                Err(_) => None,
+            }
+        };
+
+        // Backlog is limited to 50% of max_inflight
+        assert_eq!(recv_topic().await.unwrap(), "topic/backlog:1");
+        assert_eq!(recv_topic().await.unwrap(), "topic/backlog:2");
+        assert_eq!(recv_topic().await, None);
+
+        // Live data is sent while the backlog is held back
+        let mut collector = MockCollector::new("live", live, data_tx);
+        collector.send(1).await.unwrap();
+        assert!(recv_topic().await.unwrap().starts_with("topic/live"));
+        assert_eq!(recv_topic().await, None);
+
+        // Broker acks the first backlog publish, live publish and second backlog publish are inflight
+        acks.update(2);
+        assert_eq!(recv_topic().await.unwrap(), "topic/backlog:3");
+        assert_eq!(recv_topic().await, None);
+
+        // Broker acks all publishes
+        acks.update(0);
+        assert_eq!(recv_topic().await.unwrap(), "topic/backlog:4");
+        assert_eq!(recv_topic().await.unwrap(), "topic/backlog:5");
+    }
+
+    #[test]
+    // Ensures that data persisted with zstd and the stream's dictionary is read back after a restart
+    fn zstd_persistence_is_read_back_on_restart() {
+        let dir = tempdir::TempDir::new("uplink-zstd-persistence").unwrap();
+        let samples: Vec<Vec<u8>> = (0..1000)
+            .map(|i| format!("{{\"sequence\":{i},\"msg\":\"Hello, World!\"}}").into_bytes())
+            .collect();
+        let dictionary = zstd::dict::from_samples(&samples, 1024).unwrap();
+        let path = dir.path().join("hello.dict");
+        std::fs::write(&path, dictionary).unwrap();
+
+        let mut config = default_config();
+        config.persistence_path = dir.path().to_owned();
+        let persistence = Persistence {
+            max_file_size: 1024 * 1024,
+            max_file_count: 10,
+            compression: Compression::Zstd { level: 3, dictionary: Some(path) },
+            ..Default::default()
+        };
+        config.streams.insert(
+            "hello".to_owned(),
+            StreamConfig { topic: "hello/world".to_string(), persistence, ..Default::default() },
+        );
+        let config = Arc::new(config);
+        let max_packet_size = config.mqtt.max_packet_size;
+
+        let (mut serializer, _, _) = defaults(config.clone());
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "hello").unwrap();
+        for sample in &samples[..10] {
+            let publish = Publish::new("hello/world", QoS::AtLeastOnce, sample.clone());
+            write_to_storage(publish, storage).unwrap();
+        }
+        storage.flush().unwrap();
+        let file = std::fs::read(dir.path().join("hello/backup@0")).unwrap();
+        assert!(!file.windows(13).any(|w| w == b"Hello, World!"));
+        drop(serializer);
+
+        // Storage of a restarted serializer loads the dictionary to read back persisted data
+        let (mut serializer, _, _) = defaults(config);
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "hello").unwrap();
+        for sample in &samples[..10] {
+            let publish = read_from_storage(storage, max_packet_size);
+            assert_eq!(&publish.payload[..], &sample[..]);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Ensures that no data is sent while rate limit is 0, until the limit is raised
+    async fn zero_rate_limit_pauses_sending() {
+        let mut config = default_config();
+        config.rate_limit = Some(RateLimit { bytes_per_second: 0, burst: Some(1024) });
+        let stream_config =
+            StreamConfig { topic: "hello/world".to_string(), batch_size: 1, ..Default::default() };
+        config.streams.insert("hello".to_owned(), stream_config.clone());
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+        let ctrl_tx = serializer.ctrl_tx();
+
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "hello").unwrap();
+        let publish = Publish::new("hello/world", QoS::AtLeastOnce, "backlog");
+        write_to_storage(publish, storage).unwrap();
+        spawn(async move { serializer.catchup().await.unwrap() });
+
+        // Neither the backlog nor live data are sent, even with tokens left in the burst
+        let mut collector = MockCollector::new("hello", stream_config, data_tx);
+        collector.send(1).await.unwrap();
+        let recv = tokio::time::timeout(Duration::from_secs(60), net_rx.recv_async()).await;
+        assert!(recv.is_err());
+
+        // Backlog is sent once the limit is raised
+        ctrl_tx.set_rate_limit(None, Some(RateLimit { bytes_per_second: 1024, burst: None }));
+        match net_rx.recv_async().await.unwrap() {
+            Request::Publish(Publish { payload, .. }) => assert_eq!(payload, "backlog"),
+            r => unreachable!("Unexpected request: {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    // Ensures that data is counted against the budget only once sent, with usage replaced on disk
+    async fn budget_counts_only_sent_data() {
+        let dir = tempdir::TempDir::new("uplink-budget-sent").unwrap();
+        let mut config = default_config();
+        config.persistence_path = dir.path().to_owned();
+        config.budget =
+            Some(crate::config::DataBudget { daily: Some(1024), monthly: None, priority: 100 });
+        let stream_config =
+            StreamConfig { topic: "hello/world".to_string(), batch_size: 1, ..Default::default() };
+        config.streams.insert("hello".to_owned(), stream_config.clone());
+        let (mut serializer, _data_tx, net_rx) = defaults(Arc::new(config));
+
+        let storage =
+            serializer.storage_handler.map.values_mut().find(|s| s.name() == "hello").unwrap();
+        for payload in ["first", "second"] {
+            let publish = Publish::new("hello/world", QoS::AtLeastOnce, payload);
+            write_to_storage(publish, storage).unwrap();
+        }
+
+        // First publish is sent, eventloop crashes while sending the second
+        let acks = serializer.acks.clone();
+        let send = spawn(async move {
+            let status = serializer.catchup().await.unwrap();
+            (serializer, status)
+        });
+        while !net_rx.is_full() {
+            tokio::task::yield_now().await;
+        }
+        acks.update(0);
+        drop(net_rx);
+        let (mut serializer, status) = send.await.unwrap();
+        assert!(matches!(status, Status::EventLoopCrash(..)));
+        assert_eq!(serializer.budget.usage(), (5, 5));
+
+        serializer.refresh_budget();
+        let usage: Value =
+            serde_json::from_slice(&std::fs::read(dir.path().join("budget")).unwrap()).unwrap();
+        assert_eq!(usage.get("daily"), Some(&Value::from(5)));
+        assert!(!dir.path().join("budget.tmp").exists());
+    }
+
+    #[test]
+    // Ensures that upload windows which open and close at the same time are rejected
+    fn empty_upload_window_is_rejected() {
+        let config = |end: &str| {
+            serde_json::json!({
+                "topic": "hello/world",
+                "upload_windows": [{ "start": "22:00", "end": end }]
+            })
+        };
+        assert!(serde_json::from_value::<StreamConfig>(config("06:00")).is_ok());
+        let e = serde_json::from_value::<StreamConfig>(config("22:00")).unwrap_err();
+        assert!(e.to_string().contains("invalid upload window"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Ensures that data of a stream without persistence, dropped while held outside its upload
+    // windows, is counted as lost
+    async fn held_data_dropped_without_persistence() {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let window = UploadWindow {
+            start: (now + 3600).rem_euclid(86400) as u32,
+            end: (now + 7200).rem_euclid(86400) as u32,
+        };
+        let persistence = Persistence { max_file_size: 100, ..Default::default() };
+        let stream_config = StreamConfig {
+            topic: "hello/world".to_string(),
+            batch_size: 1,
+            upload_windows: vec![window],
+            persistence,
+            ..Default::default()
+        };
+        let mut config = default_config();
+        config.streams.insert("hello".to_owned(), stream_config.clone());
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        let mut collector = MockCollector::new("hello", stream_config, data_tx);
+        spawn(async move {
+            for i in 1..6 {
+                collector.send(i).await.unwrap();
+            }
+        });
+
+        // Normal mode runs until timed out, while data_tx is held
+        let _ = tokio::time::timeout(Duration::from_secs(1), serializer.normal()).await;
+        assert!(net_rx.is_empty());
+        assert!(serializer.metrics.lost_segments > 0);
+        let stream_metrics = serializer.stream_metrics.get("hello").unwrap();
+        assert_eq!(stream_metrics.lost_segments, serializer.metrics.lost_segments);
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Ensures that live data sent during catchup takes rate limit tokens only when it is sent
+    async fn live_data_takes_tokens_only_when_sent() {
+        let mut config = default_config();
+        config.mqtt.max_inflight = 10;
+        config.catchup.live_priority = Some(100);
+        let backlog =
+            StreamConfig { topic: "topic/backlog".to_string(), priority: 1, ..Default::default() };
+        let mut live = StreamConfig {
+            topic: "topic/live".to_string(),
+            batch_size: 1,
+            priority: u8::MAX,
+            ..Default::default()
+        };
+        // Burst allows a single batch of the live stream to be sent, refills very slowly
+        let data = batch("live", live.clone(), 1..2).await;
+        let size = construct_publish(data, &mut HashMap::new(), &HashMap::new(), usize::MAX)
+            .unwrap()
+            .remove(0)
+            .payload
+            .len();
+        live.rate_limit = Some(RateLimit { bytes_per_second: 1, burst: Some(size) });
+        config.streams.insert("backlog".to_owned(), backlog.clone());
+        config.streams.insert("live".to_owned(), live.clone());
+        let (mut serializer, data_tx, net_rx) = defaults(Arc::new(config));
+
+        let storage = serializer.storage_handler.map.get_mut(&backlog).unwrap();
+        for i in 1..3 {
+            let publish = Publish::new("topic/backlog", QoS::AtLeastOnce, i.to_string());
+            write_to_storage(publish, storage).unwrap();
+        }
+        spawn(async move { serializer.catchup().await.unwrap() });
+
+        // Live data fails to be sent while the eventloop is busy with the backlog, it is persisted
+        while !net_rx.is_full() {
+            tokio::task::yield_now().await;
+        }
+        let mut collector = MockCollector::new("live", live, data_tx.clone());
+        collector.send(1).await.unwrap();
+        while !data_tx.is_empty() {
+            tokio::task::yield_now().await;
+        }
+
+        // Persisted live data is sent right after the backlog, with tokens that weren't spent
+        let recv_topic = || async {
+            match tokio::time::timeout(Duration::from_secs(1), net_rx.recv_async()).await {
+                Ok(Ok(Request::Publish(Publish { topic, .. }))) => topic,
+                r => unreachable!("Unexpected request: {:?}", r),
+            }
+        };
+        assert_eq!(recv_topic().await, "topic/backlog");
+        assert_eq!(recv_topic().await, "topic/backlog");
+        assert_eq!(recv_topic().await, "topic/live");
+    }
+
+    #[tokio::test(start_paused = true)]
+    // Ensures that rate limit updates at runtime don't refill the burst
+    async fn rate_limit_updates_keep_tokens() {
+        let mut config = default_config();
+        let stream_config = StreamConfig {
+            topic: "hello/world".to_string(),
+            rate_limit: Some(RateLimit { bytes_per_second: 10, burst: Some(100) }),
+            ..Default::default()
+        };
+        config.streams.insert("hello".to_owned(), stream_config);
+        let rate_limiter = RateLimiter::new(&config);
+
+        // Burst of the stream is used up, updating its limit doesn't refill it
+        assert!(rate_limiter.try_take("hello", 100));
+        let limit = RateLimit { bytes_per_second: 10, burst: Some(200) };
+        rate_limiter.set(Some("hello"), Some(limit));
+        assert!(!rate_limiter.available("hello", 10));
+
+        // Tokens refill at the updated rate
+        rate_limiter.set(Some("hello"), Some(RateLimit { bytes_per_second: 20, burst: None }));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(rate_limiter.try_take("hello", 20));
+
+        // Same goes for the device wide limit, tokens left are capped to the new burst
+        rate_limiter.set(None, Some(RateLimit { bytes_per_second: 10, burst: Some(50) }));
+        assert!(rate_limiter.try_take("other", 20));
+        rate_limiter.set(None, Some(RateLimit { bytes_per_second: 10, burst: Some(10) }));
+        assert!(rate_limiter.try_take("other", 10));
+        assert!(!rate_limiter.available("other", 1));
+        rate_limiter.set(None, Some(RateLimit { bytes_per_second: 10, burst: Some(100) }));
+        assert!(!rate_limiter.available("other", 1));
+    }
 }
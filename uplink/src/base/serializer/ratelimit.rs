@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::{timeout, Instant};
+
+use crate::config::RateLimit;
+use crate::Config;
+
+/// Bucket of tokens(bytes) that refills at the configured rate, upto the burst size
+#[derive(Debug)]
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self { limit, tokens: limit.burst() as f64, last_refill: Instant::now() }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        let tokens = self.tokens + elapsed * self.limit.bytes_per_second as f64;
+        self.tokens = tokens.min(self.limit.burst() as f64);
+        self.last_refill = now;
+    }
+
+    /// Time until `size` bytes can be sent, payloads larger than the burst size
+    /// can be sent when the bucket is full. `None` if sending is paused, i.e. the rate is 0.
+    fn delay(&mut self, size: usize) -> Option<Duration> {
+        if self.limit.bytes_per_second == 0 {
+            return None;
+        }
+
+        self.refill();
+        let needed = size.min(self.limit.burst()) as f64;
+        if self.tokens >= needed {
+            return Some(Duration::ZERO);
+        }
+
+        let secs = (needed - self.tokens) / self.limit.bytes_per_second as f64;
+        Some(Duration::from_secs_f64(secs))
+    }
+
+    fn take(&mut self, size: usize) {
+        self.tokens -= size as f64;
+    }
+
+    /// Changes the limit, tokens left in the bucket are retained upto the new burst size
+    fn set_limit(&mut self, limit: RateLimit) {
+        self.refill();
+        self.limit = limit;
+        self.tokens = self.tokens.min(limit.burst() as f64);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buckets {
+    global: Option<TokenBucket>,
+    streams: HashMap<String, TokenBucket>,
+}
+
+impl Buckets {
+    fn delay(&mut self, stream: &str, size: usize) -> Option<Duration> {
+        let global = self.global.as_mut().map_or(Some(Duration::ZERO), |b| b.delay(size))?;
+        let stream =
+            self.streams.get_mut(stream).map_or(Some(Duration::ZERO), |b| b.delay(size))?;
+
+        Some(global.max(stream))
+    }
+
+    fn take(&mut self, stream: &str, size: usize) {
+        if let Some(bucket) = self.global.as_mut() {
+            bucket.take(size);
+        }
+        if let Some(bucket) = self.streams.get_mut(stream) {
+            bucket.take(size);
+        }
+    }
+}
+
+/// Token bucket rate limits on bytes sent by the serializer, device wide and per stream.
+/// Handles are shared, so that limits can be updated at runtime.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<Buckets>>,
+    /// Notifies senders waiting on limits that they were updated
+    notify: Arc<Notify>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &Config) -> Self {
+        let mut buckets =
+            Buckets { global: config.rate_limit.map(TokenBucket::new), ..Default::default() };
+        let streams = config
+            .streams
+            .iter()
+            .map(|(stream_name, stream_config)| (stream_name.as_str(), stream_config))
+            .chain([("action_status", &config.action_status)]);
+        for (stream_name, stream_config) in streams {
+            if let Some(limit) = stream_config.rate_limit {
+                buckets.streams.insert(stream_name.to_owned(), TokenBucket::new(limit));
+            }
+        }
+
+        Self { buckets: Arc::new(Mutex::new(buckets)), notify: Arc::new(Notify::new()) }
+    }
+
+    /// Updates rate limit of a stream, or the device wide rate limit if stream isn't mentioned.
+    /// Rate limit is removed if `limit` is `None`. Tokens left are retained when a limit is
+    /// changed, so that updates don't refill the burst.
+    pub fn set(&self, stream: Option<&str>, limit: Option<RateLimit>) {
+        let mut buckets = self.buckets.lock().unwrap();
+        match (stream, limit) {
+            (Some(stream), Some(limit)) => match buckets.streams.get_mut(stream) {
+                Some(bucket) => bucket.set_limit(limit),
+                None => _ = buckets.streams.insert(stream.to_owned(), TokenBucket::new(limit)),
+            },
+            (Some(stream), None) => {
+                buckets.streams.remove(stream);
+            }
+            (None, Some(limit)) => match buckets.global.as_mut() {
+                Some(bucket) => bucket.set_limit(limit),
+                None => buckets.global = Some(TokenBucket::new(limit)),
+            },
+            (None, None) => buckets.global = None,
+        }
+        self.notify.notify_waiters();
+    }
+
+    /// Takes tokens to send `size` bytes of stream, only if they are available right away
+    pub fn try_take(&self, stream: &str, size: usize) -> bool {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.delay(stream, size) != Some(Duration::ZERO) {
+            return false;
+        }
+
+        buckets.take(stream, size);
+        true
+    }
+
+    /// Whether tokens to send `size` bytes of stream are available right away, without taking them
+    pub fn available(&self, stream: &str, size: usize) -> bool {
+        self.buckets.lock().unwrap().delay(stream, size) == Some(Duration::ZERO)
+    }
+
+    /// Takes tokens for `size` bytes of stream that were sent
+    pub fn take(&self, stream: &str, size: usize) {
+        self.buckets.lock().unwrap().take(stream, size);
+    }
+
+    /// Waits until `size` bytes of stream can be sent and takes tokens for them, returns the time waited.
+    /// Limits are checked again whenever they are updated, e.g. sending resumes once a paused limit is raised.
+    pub async fn acquire(&self, stream: &str, size: usize) -> Duration {
+        let start = Instant::now();
+        loop {
+            // NOTE: register for notification before checking, to not miss an update in between
+            let notified = self.notify.notified();
+            let delay = {
+                let mut buckets = self.buckets.lock().unwrap();
+                match buckets.delay(stream, size) {
+                    Some(delay) if delay.is_zero() => {
+                        buckets.take(stream, size);
+                        return start.elapsed();
+                    }
+                    delay => delay,
+                }
+            };
+
+            match delay {
+                Some(delay) => _ = timeout(delay, notified).await,
+                None => notified.await,
+            }
+        }
+    }
+}
@@ -11,7 +11,7 @@ use tokio_util::codec::{Framed, LinesCodec, LinesCodecError};
 
 use std::io;
 
-use crate::base::bridge::BridgeTx;
+use crate::base::bridge::{BridgeTx, DynamicStreams};
 use crate::config::AppConfig;
 use crate::{Action, ActionResponse, Payload};
 
@@ -27,6 +27,8 @@ pub enum Error {
     Codec(#[from] LinesCodecError),
     #[error("Serde error {0}")]
     Json(#[from] serde_json::error::Error),
+    #[error("Stream {0:?} couldn't be created, more than max {1} dynamic streams")]
+    StreamLimit(String, usize),
 }
 
 #[derive(Debug, Clone)]
@@ -35,6 +37,8 @@ pub struct TcpJson {
     config: AppConfig,
     /// Bridge handle to register apps
     bridge: BridgeTx,
+    /// Handle to register streams that aren't configured
+    dynamic_streams: DynamicStreams,
     /// Action receiver
     actions_rx: Option<Receiver<Action>>,
 }
@@ -45,9 +49,10 @@ impl TcpJson {
         config: AppConfig,
         actions_rx: Option<Receiver<Action>>,
         bridge: BridgeTx,
+        dynamic_streams: DynamicStreams,
     ) -> TcpJson {
         // Note: We can register `TcpJson` itself as an app to direct actions to it
-        TcpJson { name, config, bridge, actions_rx }
+        TcpJson { name, config, bridge, dynamic_streams, actions_rx }
     }
 
     pub async fn start(self) {
@@ -98,9 +103,7 @@ impl TcpJson {
                 select! {
                     line = client.next() => {
                         let line = line.ok_or(Error::StreamDone)??;
-                        if let Err(e) = self.handle_incoming_line(line).await {
-                            error!("Error handling incoming line = {e}, app = {}", self.name);
-                        }
+                        self.handle_line(&mut client, line).await?;
                     }
                     action = actions_rx.recv_async() => {
                         let action = action?;
@@ -118,13 +121,30 @@ impl TcpJson {
             loop {
                 let line = client.next().await;
                 let line = line.ok_or(Error::StreamDone)??;
-                if let Err(e) = self.handle_incoming_line(line).await {
-                    error!("Error handling incoming line = {e}, app = {}", self.name);
-                }
+                self.handle_line(&mut client, line).await?;
             }
         }
     }
 
+    // Data rejected for the stream limit is reported back to the app, as a json line
+    async fn handle_line(
+        &self,
+        client: &mut Framed<TcpStream, LinesCodec>,
+        line: String,
+    ) -> Result<(), Error> {
+        let e = match self.handle_incoming_line(line).await {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        error!("Error handling incoming line = {e}, app = {}", self.name);
+        if let Error::StreamLimit(stream, _) = &e {
+            let error = serde_json::json!({ "stream": stream, "error": e.to_string() });
+            client.send(error.to_string()).await?;
+        }
+
+        Ok(())
+    }
+
     async fn handle_incoming_line(&self, line: String) -> Result<(), Error> {
         debug!("{}: Received line = {line:?}", self.name);
         let data = serde_json::from_str::<Payload>(&line)?;
@@ -136,6 +156,9 @@ impl TcpJson {
             return Ok(());
         }
 
+        if !self.dynamic_streams.register(&data.stream) {
+            return Err(Error::StreamLimit(data.stream, self.dynamic_streams.max()));
+        }
         self.bridge.send_payload(data).await;
 
         Ok(())
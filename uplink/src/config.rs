@@ -4,7 +4,7 @@ use std::path::PathBuf;
 use std::time::Duration;
 use std::{collections::HashMap, fmt::Debug};
 
-use serde::{Deserialize, Serialize};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 use serde_with::{serde_as, DurationSeconds};
 
 pub use crate::base::bridge::stream::MAX_BATCH_SIZE;
@@ -25,6 +25,46 @@ fn max_batch_size() -> usize {
     MAX_BATCH_SIZE
 }
 
+#[inline]
+fn default_qos() -> u8 {
+    1
+}
+
+fn deserialize_qos<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u8, D::Error> {
+    let qos = u8::deserialize(deserializer)?;
+    if qos > 2 {
+        return Err(D::Error::custom(format!("invalid qos {qos}, expected 0, 1 or 2")));
+    }
+
+    Ok(qos)
+}
+
+// Parses time of day in "HH:MM" format, as seconds since midnight
+fn deserialize_time_of_day<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    let invalid = || D::Error::custom(format!("invalid time {time:?}, expected \"HH:MM\""));
+    let (hours, minutes) = time.split_once(':').ok_or_else(invalid)?;
+    let hours: u32 = hours.parse().map_err(|_| invalid())?;
+    let minutes: u32 = minutes.parse().map_err(|_| invalid())?;
+    if hours > 23 || minutes > 59 {
+        return Err(invalid());
+    }
+
+    Ok(hours * 3600 + minutes * 60)
+}
+
+// Windows that open and close at the same time are rejected, as they are neither empty nor always open
+fn deserialize_upload_windows<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<UploadWindow>, D::Error> {
+    let windows = Vec::<UploadWindow>::deserialize(deserializer)?;
+    if windows.iter().any(|w| w.start == w.end) {
+        return Err(D::Error::custom("invalid upload window, start and end should differ"));
+    }
+
+    Ok(windows)
+}
+
 pub fn default_file_size() -> usize {
     10485760 // 10MB
 }
@@ -49,11 +89,222 @@ fn default_tcpapps() -> HashMap<String, AppConfig> {
     apps
 }
 
-#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq, PartialOrd)]
+#[derive(Debug, Clone, Deserialize, Serialize, Default, PartialEq, Eq, PartialOrd)]
 pub enum Compression {
     #[default]
     Disabled,
     Lz4,
+    /// Zstandard compression at `level`, optionally with a dictionary trained on the stream's data.
+    /// Id of the dictionary is written into the header of each compressed payload.
+    Zstd {
+        #[serde(default)]
+        level: i32,
+        dictionary: Option<PathBuf>,
+    },
+}
+
+/// Format in which a batch of data points is serialized
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, Default, PartialEq, Eq, PartialOrd)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Json,
+    Cbor,
+    #[serde(alias = "msgpack")]
+    MessagePack,
+}
+
+impl Encoding {
+    /// Suffix of topics on which batches in this encoding are published
+    pub fn topic_suffix(&self) -> &'static str {
+        match self {
+            Encoding::Json => "jsonarray",
+            Encoding::Cbor => "cborarray",
+            Encoding::MessagePack => "msgpackarray",
+        }
+    }
+}
+
+/// Daily window of time(UTC) in which data of a stream is uploaded, wraps over midnight when
+/// `end` is before `start`
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub struct UploadWindow {
+    /// Seconds since midnight at which the window opens
+    #[serde(deserialize_with = "deserialize_time_of_day")]
+    pub start: u32,
+    /// Seconds since midnight at which the window closes
+    #[serde(deserialize_with = "deserialize_time_of_day")]
+    pub end: u32,
+}
+
+impl UploadWindow {
+    pub fn contains(&self, time_of_day: u32) -> bool {
+        if self.start <= self.end {
+            self.start <= time_of_day && time_of_day < self.end
+        } else {
+            time_of_day >= self.start || time_of_day < self.end
+        }
+    }
+}
+
+/// Type that a field of data points is cast into
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CastType {
+    Number,
+    String,
+    Boolean,
+}
+
+/// Edit applied on fields of a stream's data points, before they are batched
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Transform {
+    /// Removes the field
+    Drop { field: String },
+    /// Renames the field to `to`
+    Rename { field: String, to: String },
+    /// Replaces a numeric field with `value * factor + offset`
+    Scale {
+        field: String,
+        factor: f64,
+        #[serde(default)]
+        offset: f64,
+    },
+    /// Converts the field into another type, e.g. strings of digits into numbers
+    Cast { field: String, to: CastType },
+}
+
+// NOTE: factor and offset of `Scale` aren't expected to be NaN
+impl Eq for Transform {}
+
+/// Handling of data points that don't match the stream's schema
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnInvalid {
+    #[default]
+    Drop,
+    /// Forwarded on the `<stream>_rejected` stream instead
+    Quarantine,
+    /// Forwarded with the validation error in field `_schema_error`
+    Flag,
+}
+
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct SchemaConfig {
+    /// Path to JSON Schema file that data points of the stream are validated against
+    pub path: PathBuf,
+    #[serde(default)]
+    pub on_invalid: OnInvalid,
+}
+
+/// Change in a numeric field, beyond which a data point differs from the last forwarded one
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Threshold {
+    Absolute(f64),
+    /// Percentage of the last forwarded value
+    Percent(f64),
+}
+
+/// Report-by-exception rules, data points are only forwarded if they differ from the last one
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct Deadband {
+    /// Thresholds of numeric fields, other fields are compared for equality
+    #[serde(default)]
+    pub fields: HashMap<String, Threshold>,
+    /// Duration(in seconds) of data point timestamps, after which a point is forwarded even if unchanged
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub heartbeat: Option<Duration>,
+}
+
+// NOTE: thresholds aren't expected to be NaN
+impl Eq for Deadband {}
+
+/// Function computed over values of a field, within a window
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Aggregate {
+    Count,
+    Min,
+    Max,
+    Mean,
+    Last,
+    Stddev,
+}
+
+impl Aggregate {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Aggregate::Count => "count",
+            Aggregate::Min => "min",
+            Aggregate::Max => "max",
+            Aggregate::Mean => "mean",
+            Aggregate::Last => "last",
+            Aggregate::Stddev => "stddev",
+        }
+    }
+}
+
+fn default_aggregates() -> Vec<Aggregate> {
+    vec![
+        Aggregate::Count,
+        Aggregate::Min,
+        Aggregate::Max,
+        Aggregate::Mean,
+        Aggregate::Last,
+        Aggregate::Stddev,
+    ]
+}
+
+/// Derived stream carrying aggregates of an input stream's fields, computed over tumbling windows
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq)]
+pub struct AggregationConfig {
+    /// Stream whose data points are aggregated
+    pub input: String,
+    /// Duration(in seconds) of a window, starting from the first data point received in it
+    #[serde_as(as = "DurationSeconds<u64>")]
+    pub window: Duration,
+    /// Fields that are aggregated, all fields of the input if empty
+    #[serde(default)]
+    pub fields: Vec<String>,
+    #[serde(default = "default_aggregates")]
+    pub aggregates: Vec<Aggregate>,
+    /// Raw data points of the input stream are not forwarded, they are aggregated without
+    /// applying transforms, schema or deadband of the input stream
+    #[serde(default)]
+    pub discard_input: bool,
+}
+
+/// Handling of batches of a stream while the serializer is too busy to accept them
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Data lane waits for the serializer, holding up data of all streams
+    #[default]
+    Block,
+    DropNewest,
+    /// Holds back upto `overflow_capacity` batches in memory, dropping the oldest
+    DropOldest,
+    /// Writes upto `overflow_capacity` batches to disk, dropping the oldest
+    SpillToDisk,
+}
+
+fn default_overflow_capacity() -> usize {
+    10
+}
+
+/// Order in which persisted data of a stream is sent in catchup
+#[derive(Debug, Clone, Copy, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CatchupOrder {
+    #[default]
+    OldestFirst,
+    /// Most recent data is sent first, older data is backfilled afterwards
+    NewestFirst,
 }
 
 #[serde_as]
@@ -62,6 +313,9 @@ pub struct StreamConfig {
     pub topic: String,
     #[serde(default = "max_batch_size")]
     pub batch_size: usize,
+    /// Flushes the stream before its batch grows over this many bytes, as estimated from
+    /// the size of data points serialized as json
+    pub max_batch_bytes: Option<usize>,
     #[serde(default = "default_timeout")]
     #[serde_as(as = "DurationSeconds<u64>")]
     /// Duration(in seconds) that bridge collector waits from
@@ -70,9 +324,40 @@ pub struct StreamConfig {
     #[serde(default)]
     pub compression: Compression,
     #[serde(default)]
+    pub encoding: Encoding,
+    /// Serialize batches column-wise, with field names written once and delta encoded
+    /// sequence numbers and timestamps
+    #[serde(default)]
+    pub columnar: bool,
+    #[serde(default)]
     pub persistence: Persistence,
     #[serde(default)]
     pub priority: u8,
+    #[serde(default)]
+    pub catchup_order: CatchupOrder,
+    #[serde(default)]
+    pub overflow: OverflowPolicy,
+    /// Number of batches held back by the overflow policy
+    #[serde(default = "default_overflow_capacity")]
+    pub overflow_capacity: usize,
+    /// MQTT QoS level(0, 1 or 2) that data of the stream is published with
+    #[serde(default = "default_qos", deserialize_with = "deserialize_qos")]
+    pub qos: u8,
+    /// Publishes of the stream are retained by the broker, for subscribers that join later
+    #[serde(default)]
+    pub retain: bool,
+    /// Limits the rate at which data of the stream is sent, excess data is written to storage
+    pub rate_limit: Option<RateLimit>,
+    /// Data of the stream is only written to storage outside these windows, sent otherwise
+    #[serde(default, deserialize_with = "deserialize_upload_windows")]
+    pub upload_windows: Vec<UploadWindow>,
+    /// Edits applied in order on fields of data points, before they are batched
+    #[serde(default)]
+    pub transforms: Vec<Transform>,
+    /// Validates data points before they are batched
+    pub schema: Option<SchemaConfig>,
+    /// Drops data points that don't differ meaningfully from the last forwarded one
+    pub deadband: Option<Deadband>,
 }
 
 impl Default for StreamConfig {
@@ -80,14 +365,36 @@ impl Default for StreamConfig {
         Self {
             topic: "".to_string(),
             batch_size: MAX_BATCH_SIZE,
+            max_batch_bytes: None,
             flush_period: default_timeout(),
             compression: Compression::Disabled,
+            encoding: Encoding::Json,
+            columnar: false,
             persistence: Persistence::default(),
             priority: 0,
+            catchup_order: CatchupOrder::OldestFirst,
+            overflow: OverflowPolicy::Block,
+            overflow_capacity: default_overflow_capacity(),
+            qos: default_qos(),
+            retain: false,
+            rate_limit: None,
+            upload_windows: vec![],
+            transforms: vec![],
+            schema: None,
+            deadband: None,
         }
     }
 }
 
+impl StreamConfig {
+    /// Data of the stream can be uploaded at `time_of_day`(seconds since midnight, UTC),
+    /// always true for streams without upload windows
+    pub fn in_upload_window(&self, time_of_day: u32) -> bool {
+        self.upload_windows.is_empty()
+            || self.upload_windows.iter().any(|w| w.contains(time_of_day))
+    }
+}
+
 impl Ord for StreamConfig {
     fn cmp(&self, other: &Self) -> Ordering {
         match (self.priority.cmp(&other.priority), self.topic.cmp(&other.topic)) {
@@ -103,20 +410,125 @@ impl PartialOrd for StreamConfig {
     }
 }
 
-#[derive(Debug, Copy, Clone, Deserialize, PartialEq, Eq, PartialOrd)]
+#[serde_as]
+#[derive(Debug, Clone, Deserialize, PartialEq, Eq, PartialOrd)]
 pub struct Persistence {
     #[serde(default = "default_file_size")]
     pub max_file_size: usize,
     #[serde(default)]
     pub max_file_count: usize,
+    /// Codec used to compress persistence files before they are written onto disk
+    #[serde(default)]
+    pub compression: Compression,
+    /// Duration(in seconds) after which data persisted onto disk is considered stale,
+    /// files older than this are deleted without being sent
+    #[serde(default)]
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    pub max_age: Option<Duration>,
 }
 
 impl Default for Persistence {
     fn default() -> Self {
-        Persistence { max_file_size: default_file_size(), max_file_count: 0 }
+        Persistence {
+            max_file_size: default_file_size(),
+            max_file_count: 0,
+            compression: Compression::Disabled,
+            max_age: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct DevicePersistence {
+    /// Maximum disk space(in bytes) that persistence files of all streams can occupy together
+    pub max_disk_bytes: Option<usize>,
+    /// Retain data read from persistence until the broker acknowledges receiving it
+    #[serde(default)]
+    pub delete_on_ack: bool,
+}
+
+fn default_backlog_share() -> u8 {
+    50
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CatchupConfig {
+    /// Data of streams with priority at or above this is published as soon as it is received,
+    /// instead of being written to storage behind the backlog
+    pub live_priority: Option<u8>,
+    /// Percentage of `mqtt.max_inflight` that publishes read from storage can occupy,
+    /// while live data is being sent
+    #[serde(default = "default_backlog_share")]
+    pub backlog_share: u8,
+}
+
+impl Default for CatchupConfig {
+    fn default() -> Self {
+        Self { live_priority: None, backlog_share: default_backlog_share() }
     }
 }
 
+fn default_max_dynamic_streams() -> usize {
+    20
+}
+
+/// Limits on streams that are created on receiving data, i.e. streams not in `[streams]`
+#[serde_as]
+#[derive(Debug, Clone, Deserialize)]
+pub struct DynamicStreamsConfig {
+    /// Maximum number of dynamic streams, data of further streams is rejected.
+    /// Not enforced when the simulator is configured.
+    #[serde(default = "default_max_dynamic_streams")]
+    pub max: usize,
+    /// Duration(in seconds) without data, after which a dynamic stream is flushed and removed
+    #[serde_as(as = "Option<DurationSeconds<u64>>")]
+    #[serde(default)]
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for DynamicStreamsConfig {
+    fn default() -> Self {
+        Self { max: default_max_dynamic_streams(), idle_timeout: None }
+    }
+}
+
+/// Token bucket limit on bytes sent to the broker
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_second: usize,
+    /// Bytes that can be sent at once after being idle, defaults to a second worth of data
+    pub burst: Option<usize>,
+}
+
+impl RateLimit {
+    pub fn burst(&self) -> usize {
+        self.burst.unwrap_or(self.bytes_per_second)
+    }
+}
+
+fn default_budget_priority() -> u8 {
+    u8::MAX
+}
+
+/// Limits on bytes sent to the broker each day and month, in UTC
+#[derive(Debug, Clone, Deserialize)]
+pub struct DataBudget {
+    pub daily: Option<usize>,
+    pub monthly: Option<usize>,
+    /// Streams with priority at or above this, along with action_status, are sent even after the
+    /// budget is exhausted, data of other streams is only written to storage
+    #[serde(default = "default_budget_priority")]
+    pub priority: u8,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct PersistenceEncryption {
+    /// Hex encoded 256-bit key, can be provided along with the authentication json
+    pub key: Option<String>,
+    /// Path to a file containing the hex encoded 256-bit key
+    pub key_file: Option<PathBuf>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Authentication {
     pub ca_certificate: String,
@@ -263,10 +675,30 @@ pub struct Config {
     #[serde(skip)]
     pub actions_subscription: String,
     pub streams: HashMap<String, StreamConfig>,
+    #[serde(default)]
+    pub dynamic_streams: DynamicStreamsConfig,
     #[serde(default = "default_persistence_path")]
     pub persistence_path: PathBuf,
     #[serde(default = "default_file_size")]
     pub default_buf_size: usize,
+    /// Encoding of streams that are created dynamically
+    #[serde(default)]
+    pub default_encoding: Encoding,
+    #[serde(default)]
+    pub persistence: DevicePersistence,
+    #[serde(default)]
+    pub catchup: CatchupConfig,
+    /// Device wide limit on the rate at which data is sent, excess data is written to storage
+    pub rate_limit: Option<RateLimit>,
+    /// Budget of data sent, usage is persisted across restarts
+    pub budget: Option<DataBudget>,
+    /// Derived streams, named by key, that carry windowed aggregates of other streams
+    #[serde(default)]
+    pub aggregations: HashMap<String, AggregationConfig>,
+    pub persistence_encryption: Option<PersistenceEncryption>,
+    /// Key used to encrypt persistence files, loaded from `persistence_encryption`
+    #[serde(skip)]
+    pub persistence_key: Option<[u8; 32]>,
     pub action_status: StreamConfig,
     pub stream_metrics: StreamMetricsConfig,
     pub serializer_metrics: SerializerMetricsConfig,
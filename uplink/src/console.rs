@@ -5,11 +5,13 @@ use axum::{
     http::{response::Builder, StatusCode},
     response::IntoResponse,
     routing::{get, post, put},
-    Router,
+    Json, Router,
 };
 use log::info;
+use serde::Deserialize;
 use serde_json::json;
 use uplink::base::CtrlTx;
+use uplink::config::RateLimit;
 
 use crate::ReloadHandle;
 
@@ -38,6 +40,7 @@ pub async fn start(
         .route("/disable_downloader", put(disable_downloader))
         .route("/enable_downloader", put(enable_downloader))
         .route("/status", get(status))
+        .route("/rate_limit", put(set_rate_limit))
         .with_state(state);
 
     axum::Server::bind(&address.parse().unwrap()).serve(app.into_make_service()).await.unwrap();
@@ -94,3 +97,26 @@ async fn status(State(state): State<StateHandle>) -> impl IntoResponse {
         )
         .unwrap()
 }
+
+#[derive(Debug, Deserialize)]
+struct RateLimitUpdate {
+    /// Stream to be rate limited, device wide rate limit is updated if not mentioned
+    stream: Option<String>,
+    /// Rate limit is removed if not mentioned
+    bytes_per_second: Option<usize>,
+    burst: Option<usize>,
+}
+
+// Updates rate limit on data sent by serializer, takes effect immediately
+async fn set_rate_limit(
+    State(state): State<StateHandle>,
+    Json(update): Json<RateLimitUpdate>,
+) -> impl IntoResponse {
+    info!("Updating rate limit: {update:?}");
+    let limit = update
+        .bytes_per_second
+        .map(|bytes_per_second| RateLimit { bytes_per_second, burst: update.burst });
+    state.ctrl_tx.serializer.set_rate_limit(update.stream.as_deref(), limit);
+
+    StatusCode::OK
+}
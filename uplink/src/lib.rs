@@ -40,12 +40,14 @@
 //!```
 //! [`port`]: base::AppConfig#structfield.port
 //! [`name`]: Action#structfield.name
+use std::panic::AssertUnwindSafe;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
 use anyhow::Error;
 use flume::{bounded, Receiver, RecvError, Sender};
+use futures_util::FutureExt;
 use log::error;
 
 pub mod base;
@@ -149,8 +151,10 @@ impl Uplink {
             self.data_rx.clone(),
             mqtt_client.clone(),
             self.serializer_metrics_tx(),
+            mqtt.acks(),
         )?;
         let ctrl_serializer = serializer.ctrl_tx();
+        let client_tx = serializer.client_tx();
 
         let (ctrl_tx, ctrl_rx) = bounded(1);
         let ctrl_downloader = DownloaderCtrlTx { inner: ctrl_tx };
@@ -181,7 +185,7 @@ impl Uplink {
         });
 
         // Mqtt thread to receive actions and send data
-        spawn_named_thread("Mqttio", || {
+        spawn_named_thread("Mqttio", move || {
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_time()
                 .enable_io()
@@ -189,7 +193,13 @@ impl Uplink {
                 .unwrap();
 
             rt.block_on(async {
-                mqtt.start().await;
+                // Eventloop is recreated when it crashes, serializer recovers with the new client
+                while let Err(e) = AssertUnwindSafe(mqtt.start()).catch_unwind().await {
+                    error!("Mqtt eventloop crashed, recreating it. Error = {e:?}");
+                    if client_tx.send(mqtt.recreate_eventloop()).is_err() {
+                        break;
+                    }
+                }
             })
         });
 
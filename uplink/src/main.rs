@@ -130,6 +130,23 @@ impl CommandLine {
             ))
         })?;
 
+        // Load key to encrypt persistence files with, either configured inline or from a key file
+        if let Some(encryption) = &config.persistence_encryption {
+            let key = match (&encryption.key, &encryption.key_file) {
+                (Some(key), _) => key.to_owned(),
+                (_, Some(path)) => read_to_string(path).map_err(|e| {
+                    Error::msg(format!(
+                        "Persistence key couldn't be loaded from {:?}; error = {e}",
+                        path.display()
+                    ))
+                })?,
+                _ => return Err(Error::msg("Persistence encryption requires a key or key_file")),
+            };
+            let key = hex::decode(key.trim()).ok().and_then(|key| <[u8; 32]>::try_from(key).ok());
+            let key = key.ok_or(Error::msg("Persistence key should be 32 bytes, hex encoded"))?;
+            config.persistence_key = Some(key);
+        }
+
         // replace placeholders with device/tenant ID
         let tenant_id = config.project_id.trim();
         let device_id = config.device_id.trim();
@@ -251,6 +268,7 @@ impl CommandLine {
         println!("    device_id: {}", config.device_id);
         println!("    remote: {}:{}", config.broker, config.port);
         println!("    persistence_path: {}", config.persistence_path.display());
+        println!("    persistence_encrypted: {}", config.persistence_key.is_some());
         if !config.action_redirections.is_empty() {
             println!("    action redirections:");
             for (action, redirection) in config.action_redirections.iter() {
@@ -317,7 +335,8 @@ fn main() -> Result<(), Error> {
         } else {
             None
         };
-        tcpapps.push(TcpJson::new(app, cfg, route_rx, bridge.bridge_tx()));
+        let dynamic_streams = bridge.dynamic_streams();
+        tcpapps.push(TcpJson::new(app, cfg, route_rx, bridge.bridge_tx(), dynamic_streams));
     }
 
     let simulator_actions = match &config.simulator {
@@ -4,8 +4,15 @@ use flume::{bounded, Receiver, Sender};
 use tokio::{runtime::Runtime, select};
 
 use uplink::{
-    base::bridge::{ActionsBridge, Package},
-    config::{ActionRoute, Config, StreamConfig, StreamMetricsConfig},
+    base::bridge::{
+        stream::{Stream, StreamStatus},
+        ActionsBridge, Aggregations, DataBridge, DeadbandFilter, DynamicStreams, Package, Payload,
+        Schema, StreamMetrics,
+    },
+    config::{
+        ActionRoute, AggregationConfig, Config, DynamicStreamsConfig, OnInvalid, OverflowPolicy,
+        SchemaConfig, StreamConfig, StreamMetricsConfig, Transform,
+    },
     Action, ActionResponse,
 };
 
@@ -775,3 +782,364 @@ async fn cancel_action_between_redirect() {
     assert!(status.is_failed());
     assert_eq!(status.errors, ["Action cancelled by action_id: 2"]);
 }
+
+#[test]
+fn field_transforms() {
+    let transforms: Vec<Transform> = serde_json::from_value(serde_json::json!([
+        { "op": "drop", "field": "debug" },
+        { "op": "rename", "field": "temp", "to": "temperature" },
+        { "op": "scale", "field": "speed", "factor": 0.5, "offset": 1.0 },
+        { "op": "cast", "field": "rpm", "to": "number" },
+        { "op": "cast", "field": "gear", "to": "number" },
+        { "op": "drop", "field": "missing" },
+    ]))
+    .unwrap();
+    let mut fields = serde_json::json!({
+        "debug": "x", "temp": 30, "speed": 10, "rpm": " 1200", "gear": "neutral"
+    });
+
+    let errors = transforms.iter().filter(|t| t.apply(&mut fields).is_err()).count();
+    assert_eq!(errors, 1);
+    let expected = serde_json::json!({
+        "temperature": 30, "speed": 6.0, "rpm": 1200, "gear": "neutral"
+    });
+    assert_eq!(fields, expected);
+}
+
+#[tokio::test]
+async fn windowed_aggregates() {
+    let config: AggregationConfig = serde_json::from_value(serde_json::json!({
+        "input": "motor", "window": 60, "fields": ["current", "state"],
+        "aggregates": ["count", "min", "max", "mean", "last", "stddev"], "discard_input": true
+    }))
+    .unwrap();
+    let mut aggregations = Aggregations::new(&[("motor_1m".to_owned(), config)].into());
+
+    let point = |sequence, current: f64, state| Payload {
+        stream: "motor".to_owned(),
+        sequence,
+        timestamp: 1000 + sequence as u64,
+        payload: serde_json::json!({ "current": current, "state": state, "voltage": 48 }),
+    };
+    assert!(aggregations.add(&point(1, 2.0, "idle")));
+    assert!(aggregations.add(&point(2, 4.0, "idle")));
+    assert!(aggregations.add(&point(3, 6.0, "running")));
+    let other = Payload { stream: "gps".to_owned(), ..point(4, 0.0, "") };
+    assert!(!aggregations.add(&other));
+
+    let data = aggregations.flush("motor_1m").unwrap();
+    assert_eq!((data.stream.as_str(), data.sequence, data.timestamp), ("motor_1m", 1, 1001));
+    let stddev = (8.0f64 / 3.0).sqrt();
+    let expected = serde_json::json!({
+        "current_count": 3, "current_min": 2.0, "current_max": 6.0, "current_mean": 4.0,
+        "current_last": 6.0, "current_stddev": stddev,
+        "state_count": 0, "state_min": null, "state_max": null, "state_mean": null,
+        "state_last": "running", "state_stddev": null,
+    });
+    assert_eq!(data.payload, expected);
+
+    // Window is closed, until the next data point
+    assert!(aggregations.flush("motor_1m").is_none());
+    aggregations.add(&point(5, 1.0, "idle"));
+    assert_eq!(aggregations.flush_all()[0].sequence, 2);
+}
+
+#[test]
+fn deadband_filter() {
+    let config = serde_json::from_value(serde_json::json!({
+        "fields": { "temperature": { "absolute": 0.5 }, "soc": { "percent": 10 } },
+        "heartbeat": 60
+    }))
+    .unwrap();
+    let mut filter = DeadbandFilter::new(config);
+
+    let mut pass = |timestamp, temperature: f64, soc: f64, state| {
+        let fields = serde_json::json!({ "temperature": temperature, "soc": soc, "state": state });
+        filter.pass(timestamp, &fields)
+    };
+    assert!(pass(0, 30.0, 50.0, "idle"));
+    assert!(!pass(1000, 30.4, 54.0, "idle"));
+    assert!(pass(2000, 30.6, 50.0, "idle"));
+    assert!(!pass(3000, 30.6, 46.0, "idle"));
+    assert!(pass(4000, 30.6, 44.9, "idle"));
+    assert!(pass(5000, 30.6, 44.9, "running"));
+    assert!(!pass(64000, 30.6, 44.9, "running"));
+    assert!(pass(65000, 30.6, 44.9, "running"));
+}
+
+#[test]
+fn schema_validation() {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": { "speed": { "type": "number", "minimum": 0 }, "gear": { "type": "string" } },
+        "required": ["speed"]
+    });
+    let schema = Schema::new(&schema, OnInvalid::Quarantine).unwrap();
+    assert_eq!(schema.on_invalid, OnInvalid::Quarantine);
+
+    assert!(schema.validate(&serde_json::json!({ "speed": 10, "gear": "D" })).is_ok());
+    assert!(schema.validate(&serde_json::json!({ "gear": "D" })).is_err());
+    let error = schema.validate(&serde_json::json!({ "speed": "fast" })).unwrap_err();
+    assert!(error.contains("/speed"), "{error}");
+
+    assert!(Schema::new(&serde_json::json!({ "type": 10 }), OnInvalid::Drop).is_err());
+}
+
+fn aggregation(input: &str) -> AggregationConfig {
+    serde_json::from_value(serde_json::json!({ "input": input, "window": 60 })).unwrap()
+}
+
+#[test]
+fn dynamic_stream_limit() {
+    let tmpdir = tempdir::TempDir::new("bridge").unwrap();
+    std::env::set_current_dir(&tmpdir).unwrap();
+    let config = Config {
+        streams: [("gps".to_owned(), StreamConfig::default())].into(),
+        dynamic_streams: DynamicStreamsConfig { max: 2, idle_timeout: None },
+        aggregations: [("gps_1m".to_owned(), aggregation("gps"))].into(),
+        ..default_config()
+    };
+    let dynamic_streams = DynamicStreams::new(Arc::new(config));
+
+    assert!(dynamic_streams.register("sensor_1"));
+    assert!(dynamic_streams.register("sensor_2"));
+    assert!(dynamic_streams.register("sensor_1"));
+    // Configured and derived streams aren't limited
+    assert!(dynamic_streams.register("gps"));
+    assert!(dynamic_streams.register("gps_1m"));
+    assert!(dynamic_streams.is_configured("gps_1m"));
+    assert!(!dynamic_streams.is_configured("sensor_1"));
+    assert!(!dynamic_streams.register("sensor_3"));
+    assert!(!dynamic_streams.register("sensor_3"));
+    assert_eq!(dynamic_streams.take_rejected(), [("sensor_3".to_owned(), 2)].into());
+    assert!(dynamic_streams.take_rejected().is_empty());
+
+    // Evicted streams make room for others
+    dynamic_streams.remove("sensor_2");
+    assert!(dynamic_streams.register("sensor_3"));
+}
+
+#[tokio::test]
+async fn batches_limited_by_bytes() {
+    let (tx, rx) = bounded(10);
+    let config = StreamConfig { batch_size: 100, max_batch_bytes: Some(150), ..Default::default() };
+    let mut stream = Stream::new("logs", config, tx);
+    // Estimated to be 58 bytes, with a msg of 20 characters
+    let point = |sequence, msg: &str| Payload {
+        stream: "logs".to_owned(),
+        sequence,
+        timestamp: 1,
+        payload: serde_json::json!({ "msg": msg }),
+    };
+
+    let msg = "a".repeat(20);
+    assert!(matches!(stream.fill(point(1, &msg)).await.unwrap(), StreamStatus::Init(_)));
+    assert!(matches!(stream.fill(point(2, &msg)).await.unwrap(), StreamStatus::Partial(2)));
+    assert!(matches!(stream.fill(point(3, &msg)).await.unwrap(), StreamStatus::Rollover(_)));
+    assert_eq!(rx.try_recv().unwrap().len(), 2);
+
+    // Data point larger than max_batch_bytes is sent on its own
+    let status = stream.fill(point(4, &"a".repeat(200))).await.unwrap();
+    assert!(matches!(status, StreamStatus::Flushed));
+    assert_eq!(rx.try_recv().unwrap().len(), 1);
+    assert_eq!(rx.try_recv().unwrap().len(), 1);
+    assert!(rx.is_empty());
+}
+
+#[tokio::test]
+async fn overflow_policies() {
+    let tmpdir = tempdir::TempDir::new("overflow").unwrap();
+    let point = |sequence| Payload {
+        stream: "logs".to_owned(),
+        sequence,
+        timestamp: sequence as u64,
+        payload: serde_json::json!({ "msg": "hello" }),
+    };
+    let sequences = |package: Box<dyn Package>| {
+        let points: Vec<serde_json::Value> =
+            serde_json::from_slice(&package.serialize().unwrap()).unwrap();
+        points.iter().map(|p| p["sequence"].as_u64().unwrap()).collect::<Vec<_>>()
+    };
+
+    // Serializer has room for a single batch
+    let (tx, rx) = bounded(1);
+    let config = StreamConfig {
+        batch_size: 1,
+        overflow: OverflowPolicy::DropOldest,
+        overflow_capacity: 1,
+        ..Default::default()
+    };
+    let mut stream = Stream::new("logs", config, tx);
+    for i in 1..=3 {
+        stream.fill(point(i)).await.unwrap();
+    }
+    assert_eq!(stream.metrics.overflow_drops, 1);
+    assert_eq!(sequences(rx.try_recv().unwrap()), [1]);
+    stream.send_held().await.unwrap();
+    assert_eq!(sequences(rx.try_recv().unwrap()), [3]);
+
+    let (tx, rx) = bounded(1);
+    let config =
+        StreamConfig { batch_size: 1, overflow: OverflowPolicy::SpillToDisk, ..Default::default() };
+    let mut stream = Stream::new("logs", config.clone(), tx.clone());
+    stream.set_spill_dir(tmpdir.path().join("logs"));
+    for i in 1..=3 {
+        stream.fill(point(i)).await.unwrap();
+    }
+    assert_eq!(stream.metrics.spilled_batches, 2);
+    assert_eq!(sequences(rx.try_recv().unwrap()), [1]);
+
+    // Spilled batches are picked up after a restart
+    let mut stream: Stream<Payload> = Stream::new("logs", config, tx);
+    stream.set_spill_dir(tmpdir.path().join("logs"));
+    stream.send_held().await.unwrap();
+    assert_eq!(sequences(rx.try_recv().unwrap()), [2]);
+    stream.send_held().await.unwrap();
+    assert_eq!(sequences(rx.try_recv().unwrap()), [3]);
+    assert!(rx.is_empty());
+}
+
+#[test]
+fn unloadable_schema_rejects_data() {
+    let (tx, _rx) = bounded(1);
+    let schema =
+        SchemaConfig { path: "/nonexistent/schema.json".into(), on_invalid: OnInvalid::Flag };
+    let config = StreamConfig { schema: Some(schema), ..Default::default() };
+    let stream: Stream<Payload> = Stream::new("motor", config, tx);
+
+    // Data isn't forwarded without validation, but handled as invalid as per on_invalid
+    let schema = stream.schema.unwrap();
+    assert_eq!(schema.on_invalid, OnInvalid::Flag);
+    let error = schema.validate(&serde_json::json!({ "speed": 10 })).unwrap_err();
+    assert!(error.contains("schema couldn't be loaded"), "{error}");
+}
+
+#[tokio::test]
+async fn held_batches_drained_when_stream_goes_away() {
+    let tmpdir = tempdir::TempDir::new("drain").unwrap();
+    let point = |sequence| Payload {
+        stream: "logs".to_owned(),
+        sequence,
+        timestamp: sequence as u64,
+        payload: serde_json::json!({ "msg": "hello" }),
+    };
+    let sequences = |package: Box<dyn Package>| {
+        let points: Vec<serde_json::Value> =
+            serde_json::from_slice(&package.serialize().unwrap()).unwrap();
+        points.iter().map(|p| p["sequence"].as_u64().unwrap()).collect::<Vec<_>>()
+    };
+    let recv = |rx: Receiver<Box<dyn Package>>| async move {
+        let mut received = vec![];
+        for _ in 0..3 {
+            received.extend(sequences(rx.recv_async().await.unwrap()));
+        }
+        received
+    };
+
+    for overflow in [OverflowPolicy::DropOldest, OverflowPolicy::SpillToDisk] {
+        // Serializer has room for a single batch, the rest are held back
+        let (tx, rx) = bounded(1);
+        let config =
+            StreamConfig { batch_size: 1, overflow, overflow_capacity: 2, ..Default::default() };
+        let mut stream = Stream::new("logs", config, tx);
+        stream.set_spill_dir(tmpdir.path().join(format!("{overflow:?}")));
+        for i in 1..=3 {
+            stream.fill(point(i)).await.unwrap();
+        }
+
+        // All held batches are sent, waiting for room in the serializer
+        let (drained, received) = tokio::join!(stream.drain_held(), recv(rx.clone()));
+        drained.unwrap();
+        assert_eq!(received, [1, 2, 3], "{overflow:?}");
+        assert!(rx.is_empty());
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn metrics_sent_when_all_points_are_dropped() {
+    let tmpdir = tempdir::TempDir::new("metrics").unwrap();
+    std::env::set_current_dir(&tmpdir).unwrap();
+    let schema_path = tmpdir.path().join("motor.json");
+    let schema = serde_json::json!({ "type": "object", "required": ["speed"] });
+    std::fs::write(&schema_path, schema.to_string()).unwrap();
+    let motor = serde_json::from_value(serde_json::json!({
+        "topic": "/motor",
+        "transforms": [{ "op": "cast", "field": "gear", "to": "number" }],
+        "schema": { "path": schema_path, "on_invalid": "drop" }
+    }))
+    .unwrap();
+    let gps = serde_json::from_value(serde_json::json!({
+        "topic": "/gps", "deadband": { "fields": { "lat": { "absolute": 1 } } }
+    }))
+    .unwrap();
+    let config = Config {
+        streams: [("motor".to_owned(), motor), ("gps".to_owned(), gps)].into(),
+        ..default_config()
+    };
+    let (package_tx, _package_rx) = bounded(10);
+    let (metrics_tx, metrics_rx) = bounded(10);
+    let mut bridge = DataBridge::new(Arc::new(config), package_tx, metrics_tx);
+    let data_tx = bridge.data_tx();
+    tokio::spawn(async move { bridge.start().await });
+
+    let point = |stream: &str, sequence, payload| Payload {
+        stream: stream.to_owned(),
+        sequence,
+        timestamp: sequence as u64,
+        payload,
+    };
+    data_tx.send_payload(point("gps", 1, serde_json::json!({ "lat": 10 }))).await;
+    let metrics = metrics_rx.recv_async().await.unwrap();
+    assert_eq!((metrics.stream.as_str(), metrics.points), ("gps", 1));
+
+    // Points of either stream don't make it into a batch, but are still reported
+    for i in 1..=3 {
+        data_tx.send_payload(point("motor", i, serde_json::json!({ "gear": "neutral" }))).await;
+        data_tx.send_payload(point("gps", i + 1, serde_json::json!({ "lat": 10.5 }))).await;
+    }
+    tokio::time::sleep(Duration::from_secs(15)).await;
+    let mut metrics: Vec<_> = metrics_rx.drain().collect();
+    assert_eq!(metrics.len(), 2);
+    metrics.sort_by(|a, b| a.stream.cmp(&b.stream));
+    let counts =
+        |m: &StreamMetrics| (m.points, m.transform_errors, m.schema_violations, m.deadband_drops);
+    assert_eq!((metrics[0].stream.as_str(), counts(&metrics[0])), ("gps", (0, 0, 0, 3)));
+    assert_eq!((metrics[1].stream.as_str(), counts(&metrics[1])), ("motor", (0, 3, 3, 0)));
+}
+
+#[tokio::test(start_paused = true)]
+async fn derived_streams_are_not_dynamic() {
+    let tmpdir = tempdir::TempDir::new("derived").unwrap();
+    std::env::set_current_dir(&tmpdir).unwrap();
+    let config = Config {
+        streams: [("motor".to_owned(), StreamConfig { batch_size: 1, ..Default::default() })]
+            .into(),
+        dynamic_streams: DynamicStreamsConfig {
+            max: 0,
+            idle_timeout: Some(Duration::from_secs(30)),
+        },
+        aggregations: [("motor_1m".to_owned(), aggregation("motor"))].into(),
+        ..default_config()
+    };
+    let (package_tx, package_rx) = bounded(10);
+    let (metrics_tx, _metrics_rx) = bounded(10);
+    let mut bridge = DataBridge::new(Arc::new(config), package_tx, metrics_tx);
+    let data_tx = bridge.data_tx();
+    tokio::spawn(async move { bridge.start().await });
+
+    let point = Payload {
+        stream: "motor".to_owned(),
+        sequence: 1,
+        timestamp: 1,
+        payload: serde_json::json!({ "current": 2.0 }),
+    };
+    data_tx.send_payload(point).await;
+    assert_eq!(package_rx.recv_async().await.unwrap().stream_name().as_ref(), "motor");
+
+    // Aggregates are forwarded, though no dynamic streams are allowed
+    let package = tokio::time::timeout(Duration::from_secs(300), package_rx.recv_async())
+        .await
+        .expect("aggregates weren't forwarded")
+        .unwrap();
+    assert_eq!(package.stream_name().as_ref(), "motor_1m");
+}
@@ -1,17 +1,22 @@
 use std::{fs::create_dir_all, path::PathBuf, sync::Arc, time::Duration};
 
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use flume::bounded;
-use rumqttc::{Publish, QoS, Request};
+use rumqttc::{ConnAck, ConnectReturnCode, Packet, PubComp, PubRec, Publish, QoS, Request};
 use tempdir::TempDir;
-use tokio::spawn;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+};
 
 use uplink::{
     base::{
         bridge::Payload,
+        mqtt::{Mqtt, PublishAcks},
         serializer::{write_to_storage, Serializer},
     },
-    config::{Config, Persistence, StreamConfig},
+    config::{Config, MqttConfig, Persistence, StreamConfig},
     mock::{MockClient, MockCollector},
     Storage,
 };
@@ -25,14 +30,15 @@ async fn preferential_send_on_network() {
     config.mqtt.max_packet_size = 1024 * 1024;
     config.stream_metrics.timeout = Duration::from_secs(1000);
     config.persistence_path = PathBuf::from(temp_dir.path());
-    let persistence = Persistence { max_file_size: 1024 * 1024, max_file_count: 1 };
+    let persistence =
+        Persistence { max_file_size: 1024 * 1024, max_file_count: 1, ..Default::default() };
     config.streams.extend([
         (
             "one".to_owned(),
             StreamConfig {
                 topic: "topic/one".to_string(),
                 priority: 1,
-                persistence,
+                persistence: persistence.clone(),
                 ..Default::default()
             },
         ),
@@ -41,7 +47,7 @@ async fn preferential_send_on_network() {
             StreamConfig {
                 topic: "topic/two".to_string(),
                 priority: 2,
-                persistence,
+                persistence: persistence.clone(),
                 ..Default::default()
             },
         ),
@@ -105,7 +111,8 @@ async fn preferential_send_on_network() {
     let (net_tx, req_rx) = bounded(1);
     let (metrics_tx, _metrics_rx) = bounded(1);
     let client = MockClient { net_tx };
-    let serializer = Serializer::new(config, data_rx, client, metrics_tx).unwrap();
+    let serializer =
+        Serializer::new(config, data_rx, client, metrics_tx, PublishAcks::default()).unwrap();
 
     // start serializer in the background
     spawn(async { serializer.start().await.unwrap() });
@@ -167,3 +174,92 @@ async fn preferential_send_on_network() {
     assert_eq!(topic, "topic/default");
     assert_eq!(payload, "[{\"sequence\":7,\"timestamp\":0,\"msg\":\"Hello, World!\"}]");
 }
+
+/// Reads the next packet sent by uplink, to play the role of a broker
+async fn read_packet(socket: &mut TcpStream, buf: &mut BytesMut) -> Packet {
+    loop {
+        match Packet::read(buf, 1024 * 1024) {
+            Ok(packet) => return packet,
+            Err(rumqttc::Error::InsufficientBytes(_)) => {}
+            Err(e) => panic!("Malformed packet: {e}"),
+        }
+        assert_ne!(socket.read_buf(buf).await.unwrap(), 0);
+    }
+}
+
+#[tokio::test]
+// Ensures that QoS 2 publishes sent from storage are acknowledged once the broker completes the handshake
+async fn qos2_publishes_acked_on_pubcomp() {
+    let temp_dir = TempDir::new("qos2_catchup").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut config = Config {
+        broker: "127.0.0.1".to_owned(),
+        port: listener.local_addr().unwrap().port(),
+        mqtt: MqttConfig {
+            max_packet_size: 1024 * 1024,
+            max_inflight: 10,
+            keep_alive: 60,
+            network_timeout: 5,
+        },
+        persistence_path: PathBuf::from(temp_dir.path()),
+        ..Default::default()
+    };
+    let persistence =
+        Persistence { max_file_size: 1024 * 1024, max_file_count: 1, ..Default::default() };
+    config.streams.insert(
+        "qos2".to_owned(),
+        StreamConfig { topic: "topic/qos2".to_string(), qos: 2, persistence, ..Default::default() },
+    );
+
+    let mut path = config.persistence_path.clone();
+    path.push("qos2");
+    create_dir_all(&path).unwrap();
+    let mut storage = Storage::new("qos2", 1024 * 1024);
+    storage.set_persistence(path, 1).unwrap();
+    let publish = Publish::new("topic/qos2", QoS::ExactlyOnce, "hello");
+    write_to_storage(publish, &mut storage).unwrap();
+    storage.flush().unwrap();
+
+    let config = Arc::new(config);
+    let (actions_tx, _actions_rx) = bounded(10);
+    let (mqtt_metrics_tx, _mqtt_metrics_rx) = bounded(10);
+    let mut mqtt = Mqtt::new(config.clone(), actions_tx, mqtt_metrics_tx, Default::default());
+    let acks = mqtt.acks();
+    let (_data_tx, data_rx) = bounded(1);
+    let (metrics_tx, _metrics_rx) = bounded(1);
+    let serializer =
+        Serializer::new(config, data_rx, mqtt.client(), metrics_tx, acks.clone()).unwrap();
+    spawn(async move { mqtt.start().await });
+    spawn(async { serializer.start().await.unwrap() });
+
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut buf = BytesMut::new();
+    let mut write = BytesMut::new();
+
+    assert!(matches!(read_packet(&mut socket, &mut buf).await, Packet::Connect(_)));
+    ConnAck::new(ConnectReturnCode::Success, false).write(&mut write).unwrap();
+    socket.write_all(&write.split()).await.unwrap();
+
+    // Backlog is sent with QoS 2, ignoring the subscription to actions
+    let pkid = loop {
+        match read_packet(&mut socket, &mut buf).await {
+            Packet::Publish(publish) => {
+                assert_eq!(publish.qos, QoS::ExactlyOnce);
+                assert_eq!(publish.payload, "hello");
+                break publish.pkid;
+            }
+            Packet::Subscribe(_) => continue,
+            p => unreachable!("Unexpected packet: {p:?}"),
+        }
+    };
+
+    // Publish isn't acknowledged until the broker completes the handshake
+    PubRec::new(pkid).write(&mut write).unwrap();
+    socket.write_all(&write.split()).await.unwrap();
+    assert!(matches!(read_packet(&mut socket, &mut buf).await, Packet::PubRel(_)));
+    assert_eq!(acks.acked(), 0);
+
+    PubComp::new(pkid).write(&mut write).unwrap();
+    socket.write_all(&write.split()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(5), acks.wait_for_acked(1)).await.unwrap();
+}